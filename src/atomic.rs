@@ -0,0 +1,139 @@
+//! Crash-safe file replacement.
+//!
+//! Files are never written in place. The new content goes into a temporary file in the same
+//! directory which is synced and then renamed over the target, so the target is always either
+//! completely old or completely new.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use crate::attributes::Attributes;
+
+fn temp_name(to:&Path) -> io::Result<std::path::PathBuf> {
+	let name = to.file_name()
+		.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+	let mut temp_name = std::ffi::OsString::from(".");
+	temp_name.push(name);
+	temp_name.push(format!(".{}.tmp", std::process::id()));
+	Ok(parent(to).join(temp_name))
+}
+
+fn parent(path:&Path) -> &Path {
+	match path.parent() {
+		Some(p) if !p.as_os_str().is_empty() => p,
+		_ => Path::new("."),
+	}
+}
+
+/// Sync the directory entry of `path`, so a preceding rename survives a crash.
+pub fn sync_parent(path:&Path) -> io::Result<()> {
+	File::open(parent(path))?.sync_all()
+}
+
+/// Replace `to` with a file filled by `fill`.
+pub fn write_with<F>(to:&Path, fill:F) -> io::Result<()>
+where F:FnOnce(&mut File) -> io::Result<()>
+{
+	let temp = temp_name(to)?;
+	let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp)?;
+	let result = fill(&mut file)
+		.and_then(|_|file.sync_all())
+		.and_then(|_|std::fs::rename(&temp, to))
+		.and_then(|_|sync_parent(to));
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp);
+	}
+	result
+}
+
+/// Replace `to` with the given content, and the given attributes (or the permissions and owner `to` had).
+pub fn write(to:&Path, content:&[u8], attributes:Option<&Attributes>) -> io::Result<()> {
+	use std::io::Write;
+	use std::os::unix::fs::MetadataExt;
+	let existing = match attributes {
+		None => std::fs::metadata(to).ok(),
+		Some(_) => None,
+	};
+	write_with(to, |f|{
+		f.write_all(content)?;
+		if let Some(existing) = &existing {
+			// only root can give files to others, keeping the own ownership is fine otherwise
+			match std::os::unix::fs::fchown(&*f, Some(existing.uid()), Some(existing.gid())) {
+				Err(e) if e.kind() == io::ErrorKind::PermissionDenied => log::debug!(r#"Can't keep the owner of "{}": {e}"#,to.display()),
+				result => result?,
+			}
+			f.set_permissions(existing.permissions())?;
+		}
+		attributes.map_or(Ok(()), |a|a.apply(f))
+	})
+}
+
+/// Give the copy `to` of `source` its permissions and modification time, or the given attributes.
+fn copy_attributes(source:&File, to:&File, attributes:Option<&Attributes>) -> io::Result<()> {
+	let metadata = source.metadata()?;
+	to.set_modified(metadata.modified()?)?;
+	match attributes {
+		Some(attributes) => attributes.apply(to),
+		None => to.set_permissions(metadata.permissions()),
+	}
+}
+
+/// Replace `to` with a copy of `from` (including its permissions and modification time, or the given attributes).
+pub fn copy(from:&Path, to:&Path, attributes:Option<&Attributes>) -> io::Result<u64> {
+	let mut source = File::open(from)?;
+	let mut copied = 0;
+	write_with(to, |f|{
+		copied = io::copy(&mut source, f)?;
+		copy_attributes(&source, f, attributes)
+	})?;
+	Ok(copied)
+}
+
+/// Replace `to` with a copy-on-write clone of `from` (including its permissions and modification time, or the given attributes).
+///
+/// Falls back to a plain copy if the filesystem doesn't support cloning (only btrfs, xfs and a few
+/// others do), or `from` and `to` are on different filesystems.
+pub fn reflink(from:&Path, to:&Path, attributes:Option<&Attributes>) -> io::Result<()> {
+	use std::os::fd::AsRawFd;
+	let mut source = File::open(from)?;
+	write_with(to, |f|{
+		// SAFETY: both file descriptors are valid for the duration of the call
+		if unsafe {libc::ioctl(f.as_raw_fd(), libc::FICLONE, source.as_raw_fd())} != 0 {
+			log::debug!(r#"Can't clone "{}" ({}), copying it"#,from.display(),io::Error::last_os_error());
+			io::copy(&mut source, f)?;
+		}
+		copy_attributes(&source, f, attributes)
+	})
+}
+
+/// Replace `to` with a hardlink to `from`.
+///
+/// Falls back to a copy if they are on different filesystems.
+pub fn hardlink(from:&Path, to:&Path) -> io::Result<()> {
+	let temp = temp_name(to)?;
+	let _ = std::fs::remove_file(&temp);
+	match std::fs::hard_link(from, &temp) {
+		Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+			log::debug!(r#"Can't link "{}" ({e}), copying it"#,from.display());
+			return copy(from, to, None).map(|_|());
+		}
+		result => result?,
+	}
+	let result = std::fs::rename(&temp, to).and_then(|_|sync_parent(to));
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp);
+	}
+	result
+}
+
+/// Replace `link` with a symlink pointing to `target`.
+pub fn symlink(target:&Path, link:&Path) -> io::Result<()> {
+	let temp = temp_name(link)?;
+	let _ = std::fs::remove_file(&temp);
+	let result = std::os::unix::fs::symlink(target, &temp)
+		.and_then(|_|std::fs::rename(&temp, link))
+		.and_then(|_|sync_parent(link));
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp);
+	}
+	result
+}
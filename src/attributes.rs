@@ -0,0 +1,110 @@
+//! Ownership, permissions and extended attributes of managed files.
+//!
+//! New files get the owner of the process and a label derived from their directory, so the ones
+//! the original had are recorded when a file is added and restored whenever it's replaced.
+//! Extended attributes include ACLs ("system.posix_acl_access") and SELinux labels ("security.selinux").
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{File, Permissions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use xattr::FileExt;
+
+#[derive(Deserialize,Serialize,Debug,Clone,PartialEq)]
+pub struct Attributes {
+	pub mode:u32,
+	pub uid:u32,
+	pub gid:u32,
+	/// extended attributes, values hex encoded
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub xattrs:BTreeMap<String,String>,
+}
+
+fn to_hex(bytes:&[u8]) -> String {
+	bytes.iter().map(|b|format!("{b:02x}")).collect()
+}
+fn from_hex(hex:&str) -> io::Result<Vec<u8>> {
+	(0..hex.len()).step_by(2)
+		.map(|i|hex.get(i..i+2).and_then(|b|u8::from_str_radix(b, 16).ok())
+			.ok_or(io::Error::new(io::ErrorKind::InvalidData, format!(r#"invalid hex value "{hex}""#))))
+		.collect()
+}
+
+impl Attributes {
+	/// The attributes `path` has now.
+	pub fn read(path:&Path) -> Result<Attributes,String> {
+		let error = |e:io::Error|format!(r#"Failed reading attributes of "{}": {e}"#,path.display());
+		let file = File::open(path).map_err(error)?;
+		let metadata = file.metadata().map_err(error)?;
+		let mut xattrs = BTreeMap::new();
+		for name in file.list_xattr().map_err(error)? {
+			let Some(key) = name.to_str() else {
+				log::warn!(r#"Ignoring extended attribute {name:?} of "{}""#,path.display());
+				continue;
+			};
+			if let Some(value) = file.get_xattr(&name).map_err(error)? {
+				xattrs.insert(key.to_string(), to_hex(&value));
+			}
+		}
+		Ok(Attributes{mode:metadata.mode() & 0o7777, uid:metadata.uid(), gid:metadata.gid(), xattrs})
+	}
+
+	/// These attributes with `mode` and `owner` ("user", "user:group" or ":group") replaced.
+	pub fn with_overrides(&self, mode:Option<u32>, owner:Option<&str>) -> Result<Attributes,String> {
+		let mut attributes = self.clone();
+		if let Some(mode) = mode {
+			attributes.mode = mode;
+			// the ACL would override the mode again
+			attributes.xattrs.remove("system.posix_acl_access");
+		}
+		if let Some(owner) = owner {
+			let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+			if !user.is_empty() {
+				attributes.uid = user_id(user)?;
+			}
+			if !group.is_empty() {
+				attributes.gid = group_id(group)?;
+			}
+		}
+		Ok(attributes)
+	}
+
+	/// Give `file` these attributes.
+	pub fn apply(&self, file:&File) -> io::Result<()> {
+		std::os::unix::fs::fchown(file, Some(self.uid), Some(self.gid))?;
+		for (name,value) in &self.xattrs {
+			file.set_xattr(name, &from_hex(value)?)
+				.map_err(|e|io::Error::new(e.kind(), format!(r#"setting "{name}": {e}"#)))?;
+		}
+		// after changing the owner, which clears setuid bits
+		file.set_permissions(Permissions::from_mode(self.mode))
+	}
+}
+
+fn user_id(user:&str) -> Result<u32,String> {
+	if let Ok(uid) = user.parse() {
+		return Ok(uid);
+	}
+	let name = CString::new(user).map_err(|e|format!(r#"Invalid user name "{user}": {e}"#))?;
+	// SAFETY: name is a valid C string and the result is only read before the next call
+	let passwd = unsafe {libc::getpwnam(name.as_ptr())};
+	if passwd.is_null() {
+		return Err(format!(r#"User "{user}" doesn't exist"#));
+	}
+	Ok(unsafe {(*passwd).pw_uid})
+}
+
+fn group_id(group:&str) -> Result<u32,String> {
+	if let Ok(gid) = group.parse() {
+		return Ok(gid);
+	}
+	let name = CString::new(group).map_err(|e|format!(r#"Invalid group name "{group}": {e}"#))?;
+	// SAFETY: name is a valid C string and the result is only read before the next call
+	let entry = unsafe {libc::getgrnam(name.as_ptr())};
+	if entry.is_null() {
+		return Err(format!(r#"Group "{group}" doesn't exist"#));
+	}
+	Ok(unsafe {(*entry).gr_gid})
+}
@@ -0,0 +1,610 @@
+//! The profiles file.
+//!
+//! It's read with serde, but written back by merging the changes into the document that was read,
+//! so comments, formatting and order of everything that wasn't changed are kept.
+use std::fs::File;
+use std::io::read_to_string;
+use std::path::{Path, PathBuf};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use toml_edit::{Array, DocumentMut, Item, Key, TableLike, Value};
+use crate::rules::Rules;
+use crate::template::Vars;
+use crate::transaction::Transaction;
+
+#[derive(Deserialize,Serialize,Debug,Default,Clone,PartialEq)]
+pub struct Profile{
+	/// profile to inherit files, variants and settings from
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub extends:Option<String>,
+	/// profiles of different groups can be active at the same time, activating one only replaces the one of its group
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub group:Option<String>,
+	/// managed files and directories, or glob patterns matching them
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub files:Vec<PathBuf>,
+	/// what the patterns in files matched when they were last expanded
+	#[serde(skip)]
+	pub matched:Vec<PathBuf>,
+	/// permissions of the managed files while the profile is active (instead of the original's)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub mode:Option<u32>,
+	/// owner ("user", "user:group" or ":group") of the managed files while the profile is active
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub owner:Option<String>,
+	/// managed files (or glob patterns) whose variant is a template, rendered with vars
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub templates:Vec<PathBuf>,
+	#[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+	pub vars:Vars,
+	/// when `auto` selects the profile
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub auto:Option<Rules>,
+	#[serde(flatten)]
+	pub hooks:Hooks,
+}
+
+/// Shell commands run before and after a profile (or a file) is activated or de-activated
+#[derive(Deserialize,Serialize,Debug,Default,Clone,PartialEq)]
+pub struct Hooks {
+	/// failing aborts the change
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub pre_activate:Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub post_activate:Option<String>,
+	/// failing aborts the change
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub pre_deactivate:Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub post_deactivate:Option<String>,
+}
+
+pub type Profiles = IndexMap<String,Profile>;
+
+/// The group of profiles that don't name one.
+pub const DEFAULT_GROUP:&str = "default";
+
+/// Whether an entry of [Profile::files] is a glob pattern.
+pub fn is_pattern(path:&Path) -> bool {
+	path.as_os_str().as_encoded_bytes().iter().any(|c|matches!(c, b'*' | b'?' | b'['))
+}
+
+impl Profile {
+	pub fn patterns(&self) -> impl Iterator<Item=&PathBuf> {
+		self.files.iter().filter(|f|is_pattern(f))
+	}
+	/// All files managed by the profile, with patterns replaced by what they matched.
+	pub fn managed(&self) -> impl Iterator<Item=&PathBuf> {
+		self.files.iter().filter(|f|!is_pattern(f)).chain(self.matched.iter())
+	}
+	pub fn manages(&self, file:&Path) -> bool {
+		self.managed().any(|f|f == file)
+	}
+	/// Whether nothing but the (empty) table would be written for the profile.
+	pub fn is_empty(&self) -> bool {
+		*self == Profile{matched:self.matched.clone(), ..Default::default()}
+	}
+	pub fn is_template(&self, file:&Path) -> bool {
+		self.templates.iter().any(|t|t == file ||
+			is_pattern(t) && t.to_str().and_then(|t|glob::Pattern::new(t).ok()).is_some_and(|p|p.matches_path(file)))
+	}
+}
+
+/// A profile together with everything it inherits.
+pub struct Effective<'a> {
+	pub name:&'a str,
+	/// the profile and the ones it extends, most specific first
+	chain:Vec<(&'a str,&'a Profile)>,
+}
+
+impl<'a> Effective<'a> {
+	pub fn new(profiles:&'a Profiles, name:&'a str) -> Result<Effective<'a>,String> {
+		let mut chain = vec![];
+		let mut next = Some(name);
+		while let Some(name) = next {
+			let (name,profile) = profiles.get_key_value(name)
+				.ok_or(format!(r#"Profile "{name}" doesn't exist"#))?;
+			chain.push((name.as_str(),profile));
+			next = profile.extends.as_deref();
+		}
+		Ok(Effective{name, chain})
+	}
+	/// All managed files, with the profile whose variant is used for them.
+	pub fn files(&self) -> Vec<(&'a PathBuf,&'a str)> {
+		let mut files:Vec<(&PathBuf,&str)> = vec![];
+		for (name,profile) in &self.chain {
+			for file in profile.managed() {
+				if !files.iter().any(|(f,_)|*f == file) {
+					files.push((file,name));
+				}
+			}
+		}
+		files
+	}
+	/// The profile whose variant of `file` is used.
+	pub fn variant(&self, file:&Path) -> Option<&'a str> {
+		self.chain.iter().find(|(_,p)|p.manages(file)).map(|(n,_)|*n)
+	}
+	pub fn manages(&self, file:&Path) -> bool {
+		self.variant(file).is_some()
+	}
+	pub fn is_template(&self, file:&Path) -> bool {
+		self.chain.iter().any(|(_,p)|p.is_template(file))
+	}
+	/// The variables of all inherited profiles, overridden by the more specific ones.
+	pub fn vars(&self) -> Vars {
+		let mut vars = Vars::new();
+		for (_,profile) in self.chain.iter().rev() {
+			vars.extend(profile.vars.iter().map(|(k,v)|(k.clone(),v.clone())));
+		}
+		vars
+	}
+	pub fn mode(&self) -> Option<u32> {
+		self.chain.iter().find_map(|(_,p)|p.mode)
+	}
+	pub fn owner(&self) -> Option<&'a str> {
+		self.chain.iter().find_map(|(_,p)|p.owner.as_deref())
+	}
+	/// The hooks of all inherited profiles, overridden by the more specific ones.
+	pub fn hooks(&self) -> Hooks {
+		let mut hooks = Hooks::default();
+		for (_,profile) in self.chain.iter().rev() {
+			let own = profile.hooks.clone();
+			hooks = Hooks{
+				pre_activate:own.pre_activate.or(hooks.pre_activate),
+				post_activate:own.post_activate.or(hooks.post_activate),
+				pre_deactivate:own.pre_deactivate.or(hooks.pre_deactivate),
+				post_deactivate:own.post_deactivate.or(hooks.post_deactivate),
+			};
+		}
+		hooks
+	}
+	pub fn group(&self) -> &'a str {
+		self.chain.iter().find_map(|(_,p)|p.group.as_deref()).unwrap_or(DEFAULT_GROUP)
+	}
+}
+
+/// Check that all extended profiles exist and no profile (indirectly) extends itself.
+fn check_inheritance(profiles:&Profiles) -> Result<(),String> {
+	for (name,profile) in profiles {
+		let mut chain = vec![name.as_str()];
+		let mut next = profile.extends.as_deref();
+		while let Some(base) = next {
+			let profile = profiles.get(base)
+				.ok_or(format!(r#"Profile "{}" extends "{base}", which doesn't exist"#,chain[chain.len()-1]))?;
+			if chain.contains(&base) {
+				return Err(format!("Profiles extend each other: {} -> {base}",chain.join(" -> ")));
+			}
+			chain.push(base);
+			next = profile.extends.as_deref();
+		}
+	}
+	Ok(())
+}
+
+/// The "settings" table of the profiles file
+#[derive(Deserialize,Serialize,Debug,Default,PartialEq,Clone)]
+pub struct Settings{
+	/// directory to keep originals and variants in (instead of next to the managed files)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub store:Option<PathBuf>,
+	/// how originals and variants are kept in the store
+	#[serde(default, skip_serializing_if = "Backend::is_default")]
+	pub backend:Backend,
+	/// how variants are put in place of the managed files (unless overridden per file)
+	#[serde(default, skip_serializing_if = "Strategy::is_default")]
+	pub strategy:Strategy,
+	/// what `watch` does with edits to live files
+	#[serde(default, skip_serializing_if = "Watch::is_default")]
+	pub watch:Watch,
+	/// settings for specific managed files
+	#[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+	pub files:IndexMap<PathBuf,FileSettings>,
+}
+
+#[derive(Deserialize,Serialize,Debug,Default,PartialEq,Clone)]
+pub struct FileSettings{
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub strategy:Option<Strategy>,
+	/// shell command checking an edited variant (its path replaces "{}", or is appended)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub validate:Option<String>,
+	/// run when the content of the file changes by (de-)activating a profile
+	#[serde(flatten)]
+	pub hooks:Hooks,
+}
+
+#[derive(Deserialize,Serialize,Debug,Default,PartialEq,Clone,Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+	/// replace the managed file by a copy of the variant
+	#[default]
+	Copy,
+	/// replace the managed file by a copy-on-write clone of the variant, or a copy where the filesystem can't clone
+	Reflink,
+	/// replace the managed file by a hardlink to the variant, or a copy if it's on another filesystem
+	Hardlink,
+	/// replace the managed file by a symlink to the variant (needs the files backend)
+	Symlink,
+}
+
+#[derive(Deserialize,Serialize,Debug,Default,PartialEq,Clone,Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+	/// a plain copy per variant
+	#[default]
+	Files,
+	/// content addressed, every distinct content is stored only once (needs a store directory)
+	Content,
+}
+
+/// Everything in the profiles file that's not "settings" is a profile
+#[derive(Deserialize)]
+struct Content {
+	#[serde(default)]
+	settings:Settings,
+	#[serde(flatten)]
+	profiles:Profiles,
+}
+#[derive(Serialize)]
+struct ContentRef<'a> {
+	#[serde(skip_serializing_if = "Settings::is_default")]
+	settings:&'a Settings,
+	#[serde(flatten)]
+	profiles:&'a Profiles,
+}
+
+impl Settings {
+	fn is_default(&self) -> bool {*self == Settings::default()}
+}
+#[derive(Deserialize,Serialize,Debug,Default,PartialEq,Clone,Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Watch {
+	/// capture them into the active profile managing the file
+	#[default]
+	Capture,
+	/// only report them, the file is drifted until it's captured or overwritten with --force
+	Drift,
+}
+
+impl Backend {
+	fn is_default(&self) -> bool {*self == Backend::default()}
+}
+impl Strategy {
+	fn is_default(&self) -> bool {*self == Strategy::default()}
+}
+impl Watch {
+	fn is_default(&self) -> bool {*self == Watch::default()}
+}
+impl Settings {
+	/// The strategy to use for the managed file `basename`.
+	pub fn strategy(&self, basename:&Path) -> Strategy {
+		self.files.get(basename).and_then(|f|f.strategy).unwrap_or(self.strategy)
+	}
+}
+
+pub struct Config {
+	pub settings:Settings,
+	pub profiles:Profiles,
+	/// the profiles file as it was read
+	text:String,
+	/// the document as it was read, with renamed profiles renamed in place
+	document:DocumentMut,
+	/// settings and profiles as they were read, serialized (to find what changed since)
+	read:DocumentMut,
+}
+
+fn serialize(settings:&Settings, profiles:&Profiles) -> Result<DocumentMut,String> {
+	toml::to_string_pretty(&ContentRef{settings, profiles})
+		.map_err(|e|format!("Failed to serialize configuration: {e}"))?
+		.parse().map_err(|e|format!("Failed to serialize configuration: {e}"))
+}
+
+impl Config {
+	pub fn load(profiles:&Path) -> Result<Config,String>
+	{
+		if !profiles.exists() {
+			log::warn!(r#"Profiles file "{}" doesn't exist. Creating an empty one."#,profiles.display());
+			File::create(profiles)
+				.map_err(|e|format!(r#"Profiles file "{}" could not be created ({e}). Aborting."#,profiles.display()))?;
+		} else if profiles.is_dir() {
+			Err(format!(r#"Profiles file "{}" is a directory. Aborting."#,profiles.display()))?;
+		}
+
+		let text = File::open(profiles).and_then(read_to_string)
+			.map_err(|e|format!(r#"Failed opening profiles file "{}":{e}. Aborting."#,profiles.display()))?;
+		let parse_error = |e:&dyn std::fmt::Display|format!(r#"Failed parse profiles file "{}":{e}. Aborting."#,profiles.display());
+		let content:Content = toml::from_str(text.as_str()).map_err(|e|parse_error(&e))?;
+		check_inheritance(&content.profiles).map_err(|e|parse_error(&e))?;
+		Ok(Config{
+			read:serialize(&content.settings, &content.profiles)?,
+			settings:content.settings,
+			profiles:content.profiles,
+			document:text.parse().map_err(|e|parse_error(&e))?,
+			text,
+		})
+	}
+
+	/// Rename the profile `name` to `new_name`, keeping its place and comments in the profiles file.
+	pub fn rename_profile(&mut self, name:&str, new_name:&str) {
+		if let Some(index) = self.profiles.get_index_of(name) {
+			let (_,profile) = self.profiles.shift_remove_index(index).unwrap();
+			self.profiles.shift_insert(index, new_name.to_string(), profile);
+		}
+		// renamed in what was read as well, so saving doesn't see a removed and an added profile
+		for document in [&mut self.document, &mut self.read] {
+			if let Some((key,item)) = document.as_table_mut().remove_entry(name) {
+				let mut new_key = Key::new(new_name);
+				*new_key.leaf_decor_mut() = key.leaf_decor().clone();
+				document.as_table_mut().insert_formatted(&new_key, item);
+			}
+		}
+	}
+
+	/// Stage writing the profiles file in `tx`, only touching entries that actually changed.
+	///
+	/// Entries that aren't part of the settings or profiles (or are written out although they are the
+	/// default) are kept as they are.
+	pub fn save(&self, profiles:&Path, tx:&mut Transaction) -> Result<(),String>
+	{
+		let new = serialize(&self.settings, &self.profiles)?;
+		let mut document = self.document.clone();
+		merge_table(document.as_table_mut(), self.read.as_table(), new.as_table());
+		let content = document.to_string();
+		if content != self.text {
+			tx.write(profiles, content.into_bytes());
+		}
+		Ok(())
+	}
+}
+
+fn same_value(a:&Value, b:&Value) -> bool {
+	match (a,b) {
+		(Value::String(a),Value::String(b)) => a.value() == b.value(),
+		(Value::Integer(a),Value::Integer(b)) => a.value() == b.value(),
+		(Value::Float(a),Value::Float(b)) => a.value() == b.value(),
+		(Value::Boolean(a),Value::Boolean(b)) => a.value() == b.value(),
+		(Value::Datetime(a),Value::Datetime(b)) => a.value() == b.value(),
+		(Value::Array(a),Value::Array(b)) =>
+			a.len() == b.len() && a.iter().zip(b.iter()).all(|(a,b)|same_value(a,b)),
+		(Value::InlineTable(a),Value::InlineTable(b)) =>
+			a.len() == b.len() && a.iter().all(|(k,a)|b.get(k).is_some_and(|b|same_value(a,b))),
+		_ => false,
+	}
+}
+
+/// Split `text` after its first line if that line has a comment, which belongs to what comes before it.
+fn split_comment(text:&str) -> (&str,&str) {
+	match text.find('\n') {
+		Some(end) if text[..end].contains('#') => text.split_at(end),
+		_ => ("", text),
+	}
+}
+
+/// Rebuild `old` with the elements of `new`, keeping elements (and their comments) that are in both.
+///
+/// A comment behind an element is stored in front of the next one (or as the arrays trailing), so it's
+/// moved along with the element it belongs to.
+fn merge_array(old:&mut Array, new:&Array) {
+	let prefix = |v:&Value|v.decor().prefix().and_then(|p|p.as_str()).unwrap_or_default().to_string();
+	// each element with what's in front of it and the comment behind it
+	let mut remaining:Vec<(Value,String,String)> = vec![];
+	for (i,value) in old.iter().enumerate() {
+		let text = prefix(value);
+		let (behind_previous,own) = if i == 0 {("",text.as_str())} else {split_comment(&text)};
+		if let Some(previous) = remaining.last_mut() {
+			previous.2 = behind_previous.to_string();
+		}
+		remaining.push((value.clone(), own.to_string(), String::new()));
+	}
+	let trailing = old.trailing().as_str().unwrap_or_default().to_string();
+	let (behind_last,trailing) = split_comment(&trailing);
+	if let Some(last) = remaining.last_mut() {
+		last.2 = behind_last.to_string();
+	}
+	// what's in front of the first element (e.g. nothing, or a line break and the indentation)
+	let first = remaining.first().map(|(_,own,_)|own.clone()).unwrap_or_default();
+	let multiline = first.contains('\n');
+	// new elements are indented like the first one, without its comments
+	let indent = first.rfind('\n').map_or(" ", |start|&first[start..]).to_string();
+	let mut behind = String::new();
+	old.clear();
+	for value in new.iter() {
+		let (mut value,own,comment) = match remaining.iter().position(|(o,_,_)|same_value(o,value)) {
+			Some(found) => remaining.remove(found),
+			None => (value.clone(), indent.clone(), String::new()),
+		};
+		// on a single line, the first element has no separator in front of it but all others need one
+		let own = match old.is_empty() {
+			true if !multiline => first.clone(),
+			false if own.is_empty() => indent.clone(),
+			_ => own,
+		};
+		value.decor_mut().set_prefix(std::mem::take(&mut behind) + &own);
+		if value.decor().suffix().is_none() {
+			value.decor_mut().set_suffix("");
+		}
+		behind = comment;
+		old.push_formatted(value);
+	}
+	old.set_trailing(behind + trailing);
+}
+
+fn merge_value(old:&mut Value, new:&Value) {
+	match (old,new) {
+		(Value::Array(old),Value::Array(new)) => merge_array(old,new),
+		(old,new) if !same_value(old,new) => {
+			let decor = old.decor().clone();
+			*old = new.clone();
+			*old.decor_mut() = decor;
+		}
+		_ => {}
+	}
+}
+
+fn same_item(a:&Item, b:&Item) -> bool {
+	match (a,b) {
+		(Item::Value(a),Item::Value(b)) => same_value(a,b),
+		(Item::Table(a),Item::Table(b)) =>
+			a.len() == b.len() && a.iter().all(|(k,a)|b.get(k).is_some_and(|b|same_item(a,b))),
+		(Item::None,Item::None) => true,
+		_ => false,
+	}
+}
+
+/// Apply the changes from `base` to `new` to `old`.
+fn merge_item(old:&mut Item, base:&Item, new:&Item) {
+	if same_item(base,new) {
+		return;
+	}
+	let empty = toml_edit::Table::new();
+	let base_table = base.as_table_like().unwrap_or(&empty);
+	match (old,new) {
+		(Item::Value(old),Item::Value(new)) if !new.is_inline_table() => merge_value(old,new),
+		(Item::Table(old),new) if new.is_table_like() => merge_table(old,base_table,new.as_table_like().unwrap()),
+		(Item::Value(Value::InlineTable(old)),new) if new.is_table_like() => merge_table(old,base_table,new.as_table_like().unwrap()),
+		(old,new) => *old = new.clone(),
+	}
+}
+
+/// Apply the changes from `base` to `new` to `old`, keeping order and comments of unchanged entries.
+///
+/// Entries of `old` that are in neither `base` nor `new` are left alone.
+fn merge_table<O:TableLike+?Sized,B:TableLike+?Sized,N:TableLike+?Sized>(old:&mut O, base:&B, new:&N) {
+	for (key,_) in base.iter().filter(|(k,_)|!new.contains_key(k)) {
+		old.remove(key);
+	}
+	for (key,item) in new.iter() {
+		match old.get_mut(key) {
+			Some(existing) => merge_item(existing, base.get(key).unwrap_or(&Item::None), item),
+			None => {old.insert(key,item.clone());}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::transaction::tests::temp_dir;
+
+	/// Load `text` as profiles file, apply `change` and return what saving writes.
+	fn saved(name:&str, text:&str, change:impl FnOnce(&mut Config)) -> String {
+		let dir = temp_dir(name);
+		let profiles = dir.join("profiles.toml");
+		std::fs::write(&profiles, text).unwrap();
+		let mut config = Config::load(&profiles).unwrap();
+		change(&mut config);
+		let mut tx = Transaction::new(dir.join("journal"));
+		config.save(&profiles, &mut tx).unwrap();
+		tx.apply().unwrap();
+		tx.commit();
+		let saved = std::fs::read_to_string(&profiles).unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+		saved
+	}
+
+	const PROFILES:&str = r#"# profiles of this machine
+[settings]
+strategy = "copy" # written out although it's the default
+unknown = true
+
+# at the office
+[work]
+files = [
+	"/etc/hosts", # the office's DNS
+	"/etc/resolv.conf", # search domain
+	"/etc/proxy.conf",
+] # end of files
+note = "not known to the tool"
+"#;
+
+	#[test]
+	fn unchanged_is_kept() {
+		assert_eq!(saved("config-unchanged", PROFILES, |_|{}), PROFILES);
+	}
+
+	#[test]
+	fn adding_keeps_comments_and_unknown_entries() {
+		let saved = saved("config-add", PROFILES, |config|{
+			config.profiles["work"].files.push("/etc/motd".into());
+			config.profiles.insert("home".to_string(), Profile{group:Some("network".to_string()), ..Default::default()});
+		});
+		assert_eq!(saved, PROFILES.replace(r#"	"/etc/proxy.conf",
+"#, r#"	"/etc/proxy.conf",
+	"/etc/motd",
+"#) + r#"
+[home]
+group = "network"
+"#);
+	}
+
+	#[test]
+	fn removing_keeps_comments_of_neighbours() {
+		let saved = saved("config-remove", PROFILES, |config|{
+			config.profiles["work"].files.remove(1);
+		});
+		assert_eq!(saved, PROFILES.replace("\t\"/etc/resolv.conf\", # search domain\n", ""));
+		let saved = self::saved("config-remove-first", PROFILES, |config|{
+			config.profiles["work"].files.remove(0);
+		});
+		assert_eq!(saved, PROFILES.replace("\t\"/etc/hosts\", # the office's DNS\n", ""));
+		let text = "[work]\nfiles = [\"/etc/a\", \"/etc/b\", \"/etc/c\"]\n";
+		let saved = self::saved("config-remove-single-line", text, |config|{
+			config.profiles["work"].files.remove(0);
+		});
+		assert_eq!(saved, text.replace(r#"["/etc/a", "/etc/b""#, r#"["/etc/b""#));
+		let saved = self::saved("config-reorder-single-line", text, |config|{
+			config.profiles["work"].files.swap(0, 2);
+			config.profiles["work"].files.push("/etc/d".into());
+		});
+		assert_eq!(saved, text.replace(r#"["/etc/a", "/etc/b", "/etc/c"]"#, r#"["/etc/c", "/etc/b", "/etc/a", "/etc/d"]"#));
+		let text = "work = { files = [\"/etc/a\", \"/etc/b\"] }\n";
+		let saved = self::saved("config-remove-inline", text, |config|{
+			config.profiles["work"].files.remove(0);
+		});
+		assert_eq!(saved, "work = { files = [\"/etc/b\"] }\n");
+	}
+
+	#[test]
+	fn changing_a_value_keeps_its_comment() {
+		let saved = saved("config-change", PROFILES, |config|{
+			config.settings.strategy = Strategy::Reflink;
+		});
+		assert_eq!(saved, PROFILES.replace(r#"strategy = "copy""#, r#"strategy = "reflink""#));
+	}
+
+	#[test]
+	fn rename_keeps_place_and_comment() {
+		let text = format!("{PROFILES}\n[home]\nfiles = [\"/etc/hosts\"]\n");
+		let saved = saved("config-rename", &text, |config|config.rename_profile("work", "office"));
+		assert_eq!(saved, text.replace("[work]", "[office]"));
+	}
+	#[test]
+	fn profile_without_files_is_kept() {
+		let text = r#"[work]
+files = ["/etc/a.conf"]
+group = "network" # comment
+post_activate = "systemctl reload network"
+
+[work.auto]
+hostname = "ws-*"
+"#;
+		let saved = saved("config-no-files", text, |config|{
+			let work = &mut config.profiles["work"];
+			work.files.clear();
+			assert!(!work.is_empty());
+		});
+		assert_eq!(saved, text.replace("files = [\"/etc/a.conf\"]\n", ""));
+	}
+
+	#[test]
+	fn profile_with_rules_group_or_hooks_is_not_empty() {
+		assert!(Profile{matched:vec!["/etc/a.conf".into()], ..Default::default()}.is_empty());
+		let auto = Rules{user:Some("alice".to_string()), ..Default::default()};
+		assert!(!Profile{auto:Some(auto), ..Default::default()}.is_empty());
+		assert!(!Profile{group:Some("network".to_string()), ..Default::default()}.is_empty());
+		let hooks = Hooks{post_activate:Some("true".to_string()), ..Default::default()};
+		assert!(!Profile{hooks, ..Default::default()}.is_empty());
+	}
+
+}
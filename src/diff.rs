@@ -0,0 +1,51 @@
+//! Unified diffs between versions of managed files.
+use similar::{ChangeTag, TextDiff};
+
+const BOLD:&str = "\x1b[1m";
+const CYAN:&str = "\x1b[36m";
+const RED:&str = "\x1b[31m";
+const GREEN:&str = "\x1b[32m";
+const RESET:&str = "\x1b[0m";
+
+/// Content that isn't valid UTF-8 or contains a NUL byte in its first 8000 bytes (like git does).
+pub fn is_binary(content:&[u8]) -> bool {
+	content[..content.len().min(8000)].contains(&0) || std::str::from_utf8(content).is_err()
+}
+
+fn paint(text:&str, color:&str, colored:bool) -> String {
+	if colored {format!("{color}{text}{RESET}")} else {text.to_string()}
+}
+
+/// The unified diff from `old` to `new` (None if a side doesn't exist), or None if they are the same.
+pub fn unified(old_label:&str, old:Option<&[u8]>, new_label:&str, new:Option<&[u8]>, colored:bool) -> Option<String> {
+	if old == new {
+		return None;
+	}
+	let old_label = if old.is_some() {old_label} else {"/dev/null"};
+	let new_label = if new.is_some() {new_label} else {"/dev/null"};
+	let (old,new) = (old.unwrap_or_default(), new.unwrap_or_default());
+	if is_binary(old) || is_binary(new) {
+		return Some(format!("Binary files {old_label} and {new_label} differ\n"));
+	}
+	let (old,new) = (String::from_utf8_lossy(old), String::from_utf8_lossy(new));
+	let diff = TextDiff::from_lines(old.as_ref(), new.as_ref());
+	let mut out = format!("{}\n{}\n", paint(&format!("--- {old_label}"), BOLD, colored), paint(&format!("+++ {new_label}"), BOLD, colored));
+	for hunk in diff.unified_diff().iter_hunks() {
+		out += &paint(&hunk.header().to_string(), CYAN, colored);
+		out.push('\n');
+		for change in hunk.iter_changes() {
+			let (sign,color) = match change.tag() {
+				ChangeTag::Delete => ("-", RED),
+				ChangeTag::Insert => ("+", GREEN),
+				ChangeTag::Equal => (" ", ""),
+			};
+			let line = format!("{sign}{}", change.value().trim_end_matches('\n'));
+			out += &if color.is_empty() {line} else {paint(&line, color, colored)};
+			out.push('\n');
+			if change.missing_newline() {
+				out += "\\ No newline at end of file\n";
+			}
+		}
+	}
+	Some(out)
+}
@@ -0,0 +1,151 @@
+//! Hooks of profiles and managed files.
+//!
+//! While a command stages its changes, the hooks of everything it (de-)activates are collected. The
+//! pre-hooks run right before the changes are applied, so a failing one aborts without anything
+//! being changed. The post-hooks run after the changes are committed, failing ones are reported.
+use std::path::{Path, PathBuf};
+use indexmap::IndexMap;
+use crate::config::{Effective, Hooks, Settings};
+use crate::state::State;
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Event {
+	Activate,
+	Deactivate,
+}
+
+fn command(hooks:&Hooks, event:Event, pre:bool) -> (&'static str, Option<&String>) {
+	match (event, pre) {
+		(Event::Activate, true) => ("pre_activate", hooks.pre_activate.as_ref()),
+		(Event::Activate, false) => ("post_activate", hooks.post_activate.as_ref()),
+		(Event::Deactivate, true) => ("pre_deactivate", hooks.pre_deactivate.as_ref()),
+		(Event::Deactivate, false) => ("post_deactivate", hooks.post_deactivate.as_ref()),
+	}
+}
+
+struct ProfileChange {
+	event:Event,
+	name:String,
+	group:String,
+	files:Vec<PathBuf>,
+	hooks:Hooks,
+}
+
+/// A hook to run, described by `what` in messages.
+struct Run {
+	what:String,
+	command:String,
+	env:Vec<(&'static str,String)>,
+}
+
+/// Hooks of the changes staged so far.
+pub struct Pending {
+	/// hooks of the managed files, from the settings
+	file_hooks:IndexMap<PathBuf,Hooks>,
+	/// hash of the content of those files before the command
+	before:IndexMap<PathBuf,Option<String>>,
+	profiles:Vec<ProfileChange>,
+	/// the last change of each file, and the profile whose variant it gets
+	files:IndexMap<PathBuf,(Event,Option<String>)>,
+}
+
+impl Pending {
+	pub fn new(settings:&Settings, state:&State) -> Self {
+		let file_hooks:IndexMap<PathBuf,Hooks> = settings.files.iter()
+			.filter(|(_,f)|f.hooks != Hooks::default())
+			.map(|(p,f)|(p.clone(),f.hooks.clone()))
+			.collect();
+		let before = file_hooks.keys().map(|f|(f.clone(), state.files.get(f).cloned())).collect();
+		Pending{file_hooks, before, profiles:vec![], files:IndexMap::new()}
+	}
+	/// Record that `profile` is (de-)activated.
+	pub fn profile(&mut self, event:Event, profile:&Effective) {
+		self.profiles.push(ProfileChange{
+			event,
+			name:profile.name.to_string(),
+			group:profile.group().to_string(),
+			files:profile.files().into_iter().map(|(f,_)|f.clone()).collect(),
+			hooks:profile.hooks(),
+		});
+	}
+	/// Record that `file` is set to the variant of `profile` (to the original if None).
+	///
+	/// Its hooks only run if that changes its content.
+	pub fn file(&mut self, event:Event, file:&Path, profile:Option<&str>) {
+		if self.file_hooks.contains_key(file) {
+			self.files.insert(file.to_owned(), (event, profile.map(str::to_string)));
+		}
+	}
+
+	fn runs(&self, pre:bool, state:&State) -> Vec<Run> {
+		let mut runs = vec![];
+		for change in &self.profiles {
+			let (name,Some(command)) = command(&change.hooks, change.event, pre) else {continue};
+			runs.push(Run{
+				what:format!(r#"Hook {name} of profile "{}""#, change.name),
+				command:command.clone(),
+				env:vec![
+					("PROFILE_RS_EVENT", name.to_string()),
+					("PROFILE_RS_PROFILE", change.name.clone()),
+					("PROFILE_RS_GROUP", change.group.clone()),
+					("PROFILE_RS_FILES", files_env(change.files.iter())),
+				],
+			});
+		}
+		// the same command for several files (e.g. reloading a service) only runs once
+		let mut grouped:IndexMap<(&str,&String,&Option<String>),Vec<&PathBuf>> = IndexMap::new();
+		let changed = self.files.iter().filter(|(f,_)|state.files.get(*f) != self.before[*f].as_ref());
+		for (file,(event,profile)) in changed {
+			if let (name,Some(command)) = command(&self.file_hooks[file], *event, pre) {
+				grouped.entry((name,command,profile)).or_default().push(file);
+			}
+		}
+		for ((name,command,profile),files) in grouped {
+			let mut env = vec![
+				("PROFILE_RS_EVENT", name.to_string()),
+				("PROFILE_RS_FILES", files_env(files.iter().copied())),
+			];
+			if let Some(profile) = profile {
+				env.push(("PROFILE_RS_PROFILE", profile.clone()));
+			}
+			runs.push(Run{what:format!(r#"Hook {name} of "{}""#, files[0].display()), command:command.clone(), env});
+		}
+		runs
+	}
+
+	/// Run the pre-hooks, stopping at the first that fails.
+	pub fn run_pre(&self, config:&Path, state:&State) -> Result<(),String> {
+		for run in self.runs(true, state) {
+			run.run(config).map_err(|e|format!("{e}, nothing was changed"))?;
+		}
+		Ok(())
+	}
+
+	/// Run all post-hooks, and report the ones that failed.
+	pub fn run_post(&self, config:&Path, state:&State) -> Result<(),String> {
+		let failed:Vec<String> = self.runs(false, state).into_iter()
+			.filter_map(|run|run.run(config).err())
+			.collect();
+		if failed.is_empty() {Ok(())} else {Err(failed.join("\n"))}
+	}
+}
+
+/// The files as one per line.
+fn files_env<'a>(files:impl Iterator<Item=&'a PathBuf>) -> String {
+	files.map(|f|f.to_string_lossy()).collect::<Vec<_>>().join("\n")
+}
+
+impl Run {
+	fn run(&self, config:&Path) -> Result<(),String> {
+		log::info!(r#"{}: "{}""#, self.what, self.command);
+		let status = std::process::Command::new("sh").arg("-c").arg(&self.command)
+			.envs(self.env.iter().map(|(k,v)|(k,v)))
+			.env("PROFILE_RS_CONFIG", config)
+			.status()
+			.map_err(|e|format!(r#"{} ("{}") could not be run: {e}"#, self.what, self.command))?;
+		if !status.success() {
+			return Err(format!(r#"{} ("{}") failed ({status})"#, self.what, self.command));
+		}
+		Ok(())
+	}
+}
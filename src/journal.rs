@@ -0,0 +1,169 @@
+//! Write-ahead journal of a running [Transaction](crate::transaction::Transaction).
+//!
+//! Before any file is changed, the journal lists all files that are about to be touched together with
+//! where their backups will be. If the process dies before the transaction is committed, the next
+//! invocation finds the journal and restores all files from it.
+use std::fs::File;
+use std::io::read_to_string;
+use std::io::ErrorKind::NotFound;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::atomic;
+
+#[derive(Deserialize,Serialize,Debug,Default)]
+pub struct Journal {
+	/// the process running the transaction
+	pub pid:u32,
+	/// all changes are done, only the backups are left to be removed
+	pub committed:bool,
+	pub files:Vec<Entry>,
+}
+
+#[derive(Deserialize,Serialize,Debug)]
+pub struct Entry {
+	pub path:PathBuf,
+	/// where the previous version is kept (None if the file didn't exist before)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub backup:Option<PathBuf>,
+	/// path is a directory, its backup is the directory itself moved out of the way
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub dir:bool,
+}
+
+pub fn journal_path(profiles:&Path) -> PathBuf {
+	profiles.with_added_extension("journal")
+}
+
+impl Journal {
+	pub fn load(path:&Path) -> Result<Option<Journal>,String> {
+		if !path.exists() {
+			return Ok(None);
+		}
+		File::open(path).and_then(read_to_string)
+			.map_err(|e|format!(r#"Failed opening journal "{}":{e}. Aborting."#,path.display()))
+			.and_then(|s|
+				toml::from_str(s.as_str()).map_err(|e|format!(r#"Failed parse journal "{}":{e}. Aborting."#,path.display()))
+			).map(Some)
+	}
+	pub fn save(&self, path:&Path) -> Result<(),String> {
+		let content = toml::to_string_pretty(self)
+			.map_err(|e|format!("Failed to serialize journal: {e}"))?;
+		atomic::write(path, content.as_bytes(), None)
+			.map_err(|e|format!(r#"Failed writing journal "{}": {e}"#,path.display()))
+	}
+	pub fn remove(path:&Path) -> Result<(),String> {
+		std::fs::remove_file(path)
+			.and_then(|_|atomic::sync_parent(path))
+			.map_err(|e|format!(r#"Failed to remove journal "{}": {e}"#,path.display()))
+	}
+
+	/// Restore all files from their backups (or remove them if they didn't exist), and return a report of what was done.
+	///
+	///
+	/// Files that were never touched are skipped.
+	pub fn roll_back(&self) -> String {
+		let mut restored = vec![];
+		let mut failed = vec![];
+		for Entry{path,backup,dir} in self.files.iter().rev() {
+			let result = match backup {
+				Some(backup) if !backup.exists() => continue,
+				Some(backup) if *dir => std::fs::rename(backup, path),
+				// renaming a hardlink onto itself does nothing, so the backup might still be around
+				Some(backup) => std::fs::rename(backup, path).map(|_|{let _ = std::fs::remove_file(backup);}),
+				None => match if *dir {std::fs::remove_dir(path)} else {std::fs::remove_file(path)} {
+					Err(e) if e.kind() == NotFound => continue,
+					r => r,
+				},
+			}.and_then(|_|atomic::sync_parent(path));
+			match result {
+				Ok(_) => restored.push(format!("\n\t{}",path.display())),
+				Err(e) => failed.push(match backup {
+					Some(backup) => format!("\n\t{} (backup is at {}): {e}",path.display(),backup.display()),
+					None => format!("\n\t{}: {e}",path.display()),
+				}),
+			}
+		}
+		let mut report = if restored.is_empty() {
+			"Nothing had to be rolled back".to_string()
+		} else {
+			format!("Rolled back:{}", restored.concat())
+		};
+		if !failed.is_empty() {
+			report += &format!("\nFailed to roll back:{}", failed.concat());
+		}
+		report
+	}
+
+	pub fn remove_backups(&self) {
+		for Entry{backup,dir,..} in &self.files {
+			let Some(backup) = backup else {continue};
+			match if *dir {std::fs::remove_dir_all(backup)} else {std::fs::remove_file(backup)} {
+				Err(e) if e.kind() != NotFound => log::warn!(r#"Failed to remove backup "{}": {e}"#, backup.display()),
+				_ => {}
+			}
+		}
+	}
+}
+
+/// Finish or roll back a transaction that was interrupted.
+///
+/// Must be called before anything else touches the managed files or the profiles file.
+pub fn recover(profiles:&Path) -> Result<(),String> {
+	let path = journal_path(profiles);
+	let Some(journal) = Journal::load(&path)? else {return Ok(())};
+	if journal.committed {
+		log::warn!("Found finished operation of process {}, removing leftover backups",journal.pid);
+		journal.remove_backups();
+	} else {
+		log::warn!("Found unfinished operation of process {}, rolling it back",journal.pid);
+		let report = journal.roll_back();
+		if report.contains("Failed to roll back") {
+			return Err(format!("{report}\nFix these files, then remove \"{}\"",path.display()));
+		}
+		log::warn!("{report}");
+	}
+	Journal::remove(&path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::transaction::Transaction;
+	use crate::transaction::tests::temp_dir;
+
+	#[test]
+	fn recover_rolls_back_interrupted() {
+		let dir = temp_dir("recover");
+		let profiles = dir.join("profiles.toml");
+		let (changed,created) = (dir.join("changed"), dir.join("created"));
+		std::fs::write(&changed, "old").unwrap();
+		let mut tx = Transaction::new(journal_path(&profiles));
+		tx.write(&changed, b"new".to_vec());
+		tx.write(&created, b"created".to_vec());
+		tx.apply().unwrap();
+		// the process dies before committing
+		drop(tx);
+		recover(&profiles).unwrap();
+		assert_eq!(std::fs::read_to_string(&changed).unwrap(), "old");
+		assert!(!created.exists());
+		assert!(!journal_path(&profiles).exists());
+		assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn recover_finishes_committed() {
+		let dir = temp_dir("recover-committed");
+		let profiles = dir.join("profiles.toml");
+		let (file,backup) = (dir.join("file"), dir.join(".file.bak"));
+		std::fs::write(&file, "new").unwrap();
+		std::fs::write(&backup, "old").unwrap();
+		let journal = Journal{pid:1, committed:true, files:vec![Entry{path:file.clone(), backup:Some(backup.clone()), dir:false}]};
+		journal.save(&journal_path(&profiles)).unwrap();
+		recover(&profiles).unwrap();
+		assert_eq!(std::fs::read_to_string(&file).unwrap(), "new");
+		assert!(!backup.exists());
+		assert!(!journal_path(&profiles).exists());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
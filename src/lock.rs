@@ -0,0 +1,77 @@
+//! Lock against concurrent invocations on the same profiles file.
+//!
+//! Commands changing files take it exclusively, commands only reading take it shared, so they can run
+//! side by side but never while something is changed.
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::ErrorKind::{NotFound, PermissionDenied, ReadOnlyFilesystem};
+use std::io::{read_to_string, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Held for the whole command, the lock is released when this is dropped.
+pub struct Lock {
+	_file:Option<File>,
+}
+
+pub fn lock_path(profiles:&Path) -> PathBuf {
+	profiles.with_added_extension("lock")
+}
+
+fn holder(file:&mut File) -> String {
+	match read_to_string(file).map(|s|s.trim().to_string()) {
+		Ok(pid) if !pid.is_empty() => format!("process {pid}"),
+		_ => "another process".to_string(),
+	}
+}
+
+impl Lock {
+	/// Take the lock for `profiles` exclusively, waiting for other holders to release it if `wait` is set.
+	pub fn acquire(profiles:&Path, wait:bool) -> Result<Lock,String> {
+		let path = lock_path(profiles);
+		let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+			.map_err(|e|format!(r#"Failed to open lock file "{}": {e}"#,path.display()))?;
+		Self::lock(&mut file, profiles, wait, false)?;
+		file.set_len(0)
+			.and_then(|_|file.rewind())
+			.and_then(|_|writeln!(file, "{}", std::process::id()))
+			.map_err(|e|format!(r#"Failed writing lock file "{}": {e}"#,path.display()))?;
+		Ok(Lock{_file:Some(file)})
+	}
+
+	/// Take the lock for `profiles` shared with other readers, waiting for an exclusive holder to release it if `wait` is set.
+	///
+	/// Where the lock file can't be created (e.g. as the profiles file belongs to someone else) nothing can be changed
+	/// either, so it goes without a lock.
+	pub fn acquire_shared(profiles:&Path, wait:bool) -> Result<Lock,String> {
+		let path = lock_path(profiles);
+		let opened = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+			.or_else(|_|File::open(&path));
+		let mut file = match opened {
+			Ok(file) => file,
+			Err(e) if matches!(e.kind(), NotFound | PermissionDenied | ReadOnlyFilesystem) => {
+				log::debug!(r#"Can't open lock file "{}" ({e}), reading without a lock"#,path.display());
+				return Ok(Lock{_file:None});
+			}
+			Err(e) => return Err(format!(r#"Failed to open lock file "{}": {e}"#,path.display())),
+		};
+		Self::lock(&mut file, profiles, wait, true)?;
+		// the process that wrote its id isn't holding the lock anymore
+		let _ = file.set_len(0);
+		Ok(Lock{_file:Some(file)})
+	}
+
+	fn lock(file:&mut File, profiles:&Path, wait:bool, shared:bool) -> Result<(),String> {
+		let path = lock_path(profiles);
+		match if shared {file.try_lock_shared()} else {file.try_lock()} {
+			Ok(_) => Ok(()),
+			Err(TryLockError::WouldBlock) if wait => {
+				log::info!(r#"Profiles file "{}" is locked by {}, waiting .."#,profiles.display(),holder(file));
+				(if shared {file.lock_shared()} else {file.lock()})
+					.map_err(|e|format!(r#"Failed to lock "{}": {e}"#,path.display()))
+			}
+			Err(TryLockError::WouldBlock) => Err(format!(
+				r#"Profiles file "{}" is locked by {}. Use --wait to wait for it."#,profiles.display(),holder(file)
+			)),
+			Err(TryLockError::Error(e)) => Err(format!(r#"Failed to lock "{}": {e}"#,path.display())),
+		}
+	}
+}
@@ -1,13 +1,35 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{read_to_string, Write};
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use clap::ValueHint::{FilePath};
 use clap_logflag::{LogDestinationConfig, LoggingConfig};
 use log::LevelFilter;
-use serde::{Deserialize, Serialize};
+use output::Format;
+use attributes::Attributes;
+use config::{is_pattern, Config, Effective, Profiles, Watch, DEFAULT_GROUP};
+use hooks::{Event, Pending};
+use state::{Activation, State};
+use store::Store;
+use transaction::Transaction;
+use tree::Node;
+
+mod atomic;
+mod attributes;
+mod config;
+mod diff;
+mod hooks;
+mod journal;
+mod lock;
+mod output;
+mod rules;
+mod state;
+mod template;
+mod store;
+mod transaction;
+mod tree;
+mod watch;
 
 /// A basic cli tool to manage (configuration) files based on profiles.
 #[derive(Parser)]
@@ -18,6 +40,18 @@ pub struct Cli {
 	/// profiles file
 	#[arg(long, value_hint = FilePath, default_value = "profiles.toml")]
 	pub config: PathBuf,
+	/// overwrite managed files even if they were modified since they were last written
+	#[arg(long)]
+	pub force: bool,
+	/// wait for other invocations on the same profiles file to finish (default for commands only reading)
+	#[arg(long, overrides_with = "no_wait")]
+	pub wait: bool,
+	/// fail if another invocation is working on the same profiles file (default for commands changing files)
+	#[arg(long, overrides_with = "wait")]
+	pub no_wait: bool,
+	/// output format of list, profiles, status and diff
+	#[arg(long, value_enum, global = true, default_value_t = Format::Text)]
+	pub format: Format,
 	#[clap(flatten)]
 	log: clap_logflag::LogArgs,
 }
@@ -38,128 +72,1171 @@ pub enum Commands {
 	},
 	/// List files managed by the specified profile
 	List { profile:String },
-	/// Activate a specific profile (de-activates all others)
-	Activate { profile:String },
-	/// De-activate all profiles resetting all managed files into their original state
-	DeActivate,
+	/// List all profiles with the number of files they manage
+	Profiles,
+	/// Rename a profile together with its variants
+	Rename { profile:String, new_name:String },
+	/// Create a new profile as a copy of another one, including its variants
+	Clone { profile:String, new_name:String },
+	/// Delete a profile and its variants (de-activating it first if it's active)
+	Delete { profile:String },
+	/// Activate profiles (de-activates all others of their groups)
+	///
+	/// Profiles of different groups can be activated together, as long as they don't manage the same files.
+	Activate {
+		#[arg(num_args(1..))]
+		profile:Vec<String>
+	},
+	/// Activate the first profile of each group whose `auto` rules match
+	///
+	/// Profiles that are already active are left alone.
+	Auto,
+	/// De-activate all profiles (or those of the given groups) resetting their files into their original state
+	DeActivate { group:Vec<String> },
+	/// Show the active profiles and the state of all managed files
+	Status,
+	/// Copy the current content of managed files into a profile
+	///
+	/// Captures into the active profile managing each file if none is given, or into the originals if no active profile manages it.
+	/// If no files are given all files of the profile are captured.
+	Capture {
+		profile:Option<String>,
+		file: Vec<PathBuf>
+	},
+	/// Show what activating a profile changes, as unified diffs
+	///
+	/// Compares the variants of the profile with the originals, with the live files (--live), or with the
+	/// variants of another profile if the next argument names one ("org" stands for the originals).
+	Diff {
+		profile:String,
+		/// another profile and/or the files to compare (all files of the profiles if no files are given)
+		other:Vec<String>,
+		/// compare with the live files instead of the originals
+		#[arg(long)]
+		live:bool,
+		#[arg(long, value_enum, default_value_t = Color::Auto)]
+		color:Color,
+	},
+	/// Open the variant of a file in $VISUAL (or $EDITOR), and put it in place if it's active
+	///
+	/// "org" edits the original.
+	Edit {
+		profile:String,
+		file:PathBuf,
+		/// shell command to check the edited file with before it's saved, its path replaces "{}" (or is appended)
+		#[arg(long)]
+		validate:Option<String>,
+	},
+	/// Watch the managed files and the profiles file until SIGTERM (or SIGINT)
+	///
+	/// Edits to live files are captured into the active profile managing them (or only reported, see the
+	/// "watch" setting), and the active profiles are applied again whenever the profiles file changes.
+	Watch,
+	/// Move originals and variants kept next to the managed files into the configured store directory
+	MigrateStore,
+	/// Check that all originals and variants exist and are intact
+	Verify,
 }
 
-#[derive(Deserialize,Serialize,Debug,Default)]
-struct Profile{files:Vec<PathBuf>}
+#[derive(ValueEnum, Clone, Copy)]
+pub enum Color {
+	/// if the output is a terminal
+	Auto,
+	Always,
+	Never,
+}
 
-fn get_profiles(profiles:&PathBuf) -> Result<HashMap<String,Profile>,String>
+/// Make `basename` absolute and resolve all symlinks, except for the file itself (which might be a link to a variant).
+fn canonicalize(basename:&Path) -> Result<PathBuf,String>{
+	let error = |e|format!(r#"Failed to canonicalize "{}":{e}"#, basename.display());
+	let name = basename.file_name()
+		.ok_or(error(std::io::Error::from(std::io::ErrorKind::InvalidInput)))?;
+	let parent = match basename.parent() {
+		Some(p) if !p.as_os_str().is_empty() => p,
+		_ => Path::new("."),
+	};
+	let basename = parent.canonicalize().map_err(error)?.join(name);
+	basename.symlink_metadata().map_err(error)?;
+	Ok(basename)
+}
+
+/// All managed files (of all profiles)
+fn managed_files(profiles: &Profiles) -> Vec<&PathBuf>
 {
-	if !profiles.exists() {
-		log::warn!(r#"Profiles file "{}" doesn't exist. Creating an empty one."#,profiles.display());
-		File::create(&profiles)
-			.map_err(|e|format!(r#"Profiles file "{}" could not be created ({e}). Aborting."#,profiles.display()))?;
-	} else if profiles.is_dir() {
-		Err(format!(r#"Profiles file "{}" is a directory. Aborting."#,profiles.display()))?;
-	}
+	let mut files:Vec<_> = profiles.values().flat_map(|p|p.managed()).collect();
+	files.sort();
+	files.dedup();
+	files
+}
 
-	File::open(&profiles).and_then(read_to_string)
-		.map_err(|e|format!(r#"Failed opening profiles file "{}":{e}. Aborting."#,profiles.display()))
-		.and_then(|s|
-			toml::from_str(s.as_str()).map_err(|e|format!(r#"Failed parse profiles file "{}":{e}. Aborting."#,profiles.display()))
-		)
+/// The profiles active in any group (ignoring ones that were removed from the profiles file since).
+fn active_profiles<'a>(profiles: &'a Profiles, state: &State) -> Vec<Effective<'a>>
+{
+	state.active_profiles()
+		.filter_map(|name|profiles.get_key_value(name))
+		.filter_map(|(name,_)|Effective::new(profiles, name).ok())
+		.collect()
 }
 
-fn make_canon_names(basename:&Path,profile_name:&str) -> Result<(PathBuf,PathBuf, PathBuf),String>{
-	let basename= basename.canonicalize()
-		.map_err(|e|format!(r#"Failed to canonicalize "{}":{e}"#, basename.display()))?;
-	let mut new_filename = basename.to_owned();
-	new_filename.add_extension(profile_name);
-	let mut org_filename = basename.to_owned();
-	org_filename.add_extension("org");
-	Ok((basename,new_filename,org_filename))
+/// The variant in place of `basename` while `active` are active: the one of the profile managing it, or the original.
+fn live_variant<'a>(basename:&Path, active:&[Effective<'a>]) -> &'a str
+{
+	active.iter().find_map(|p|p.variant(basename)).unwrap_or("org")
 }
 
-fn copy_file(from:&Path,to:&Path) -> Result<u64, String> {
-	log::debug!(r#"Creating "{}" as a copy of "{}""#,to.display(),from.display());
-	std::fs::copy(from, to)
-		.map_err(|e|format!(r#"Error copying "{}" to "{}": {e}"#, from.display(), to.display()))
+/// Find managed files that were changed since they were last written by us.
+fn find_drifted<'a>(files:impl Iterator<Item=&'a PathBuf>, store: &Store, state: &State) -> Result<Vec<PathBuf>,String>
+{
+	let mut drifted = vec![];
+	for basename in files {
+		if basename.is_symlink() {
+			// edits to a file linked to a variant are meant to land there
+			if !store.is_own_link(basename) {
+				log::warn!(r#""{}" is a symlink to something else than its variants"#,basename.display());
+				drifted.push(basename.clone());
+			}
+			continue;
+		}
+		if basename.symlink_metadata().is_err() {
+			log::warn!(r#""{}" is missing"#,basename.display());
+			drifted.push(basename.clone());
+			continue;
+		}
+		match state.files.get(basename) {
+			Some(hash) => if tree::hash_path(basename)? != *hash {drifted.push(basename.clone())},
+			None => log::debug!(r#"No recorded content for "{}", skipping drift check"#,basename.display()),
+		}
+	}
+	Ok(drifted)
 }
-fn add_profiles(name:&String, basenames:&Vec<PathBuf>, profiles: &mut HashMap<String,Profile>) -> Result<(),String>
+
+fn check_drift(drifted:Vec<PathBuf>, force:bool) -> Result<(),String>
 {
-	basenames.iter().map(|b|add_profile(name,b,profiles))
+	if drifted.is_empty() {
+		return Ok(());
+	}
+	let list = drifted.iter().map(|f|format!("\n\t{}",f.display())).collect::<String>();
+	if force {
+		log::warn!("Overwriting modified files:{list}");
+		return Ok(());
+	}
+	if std::io::stdin().is_terminal() {
+		eprint!("The following managed files were modified since they were last written:{list}\nOverwrite them? [y/N] ");
+		let mut answer = String::new();
+		std::io::stdin().read_line(&mut answer).map_err(|e|format!("Failed reading answer: {e}"))?;
+		if answer.trim().eq_ignore_ascii_case("y") {
+			return Ok(());
+		}
+	}
+	Err(format!("The following managed files were modified since they were last written:{list}\nUse --force to overwrite them."))
+}
+
+fn add_profiles(name:&String, basenames:&[PathBuf], profiles: &mut Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
+{
+	basenames.iter().map(|b|add_profile(name,b,profiles,store,state,tx))
 		.collect::<Result<Vec<_>,_>>().map(|_|())
 }
-fn add_profile(name:&String, basename:&Path, profiles: &mut HashMap<String,Profile>) -> Result<(),String>
+fn check_name(name:&str) -> Result<(),String>
 {
-	if name == "org" {
-		return Err(r#"The profile name "org" is reserved, please use another"#.to_string())
+	if name == "org" || name == "settings" {
+		return Err(format!(r#"The profile name "{name}" is reserved, please use another"#))
 	}
+	Ok(())
+}
 
-	let (basename,new_name, org_name) = make_canon_names(basename, name)?;
-	let profile = profiles.entry(name.clone()).or_insert(Default::default());
-	if profile.files.contains(&basename) {
+fn add_profile(name:&String, basename:&Path, profiles: &mut Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
+{
+	check_name(name)?;
+
+	if is_pattern(basename) {
+		// the files it matches are added when it's expanded
+		let pattern = std::path::absolute(basename)
+			.map_err(|e|format!(r#"Failed to make "{}" absolute: {e}"#,basename.display()))?;
+		let profile = profiles.entry(name.clone()).or_default();
+		if profile.files.contains(&pattern) {
+			log::warn!(r#"Ignoring already registered "{}""#,pattern.display());
+		} else {
+			profile.files.push(pattern.clone());
+			log::info!(r#"Added pattern "{}" to profile "{name}""#,pattern.display());
+		}
+		return Ok(());
+	}
+
+	let basename = canonicalize(basename)?;
+	let managed = profiles.values().any(|p|p.manages(&basename));
+	// an inherited file is overridden starting from the variant it inherits
+	let inherited = match profiles.contains_key(name) {
+		true => Effective::new(profiles, name)?.variant(&basename).map(str::to_owned),
+		false => None,
+	};
+	let profile = profiles.entry(name.clone()).or_default();
+	if profile.manages(&basename) {
 		log::warn!(r#"Ignoring already registered "{}""#,basename.display());
 		return Ok(());
 	}
-	copy_file(&basename, &org_name)?;
-	copy_file(&basename, &new_name)?;
+	if basename.is_symlink() && !store.is_own_link(&basename) {
+		log::warn!(r#""{}" is a symlink, it will be replaced when activating a profile"#,basename.display());
+	}
+	if let Some(inherited) = inherited {
+		store.copy_variant(&basename, &inherited, name, tx)?;
+	} else if managed {
+		// the original already exists, and is what the file will be reset to
+		store.copy_variant(&basename, "org", name, tx)?;
+	} else {
+		store.put(&basename, "org", &basename, tx)?;
+		store.put(&basename, name, &basename, tx)?;
+		state.files.insert(basename.clone(), tree::hash_path(&basename)?);
+		if basename.is_file() {
+			state.attributes.insert(basename.clone(), Attributes::read(&basename)?);
+		}
+	}
 
 	profile.files.push(basename.clone());
 	log::info!(r#"Added "{}" to profile "{name}""#,basename.display());
 	Ok(())
 }
 
-fn remove_profiles(name:&String, basenames:&Vec<PathBuf>, profiles: &mut HashMap<String,Profile>) -> Result<(),String>
+fn remove_profiles(name:&String, basenames:&[PathBuf], profiles: &mut Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
 {
-	basenames.iter().map(|b|remove_profile(name,b,profiles))
+	basenames.iter().map(|b|remove_profile(name,b,profiles,store,state,tx))
 		.collect::<Result<Vec<_>,_>>().map(|_|())
 }
-fn remove_profile(name:&String, basename:&Path, profiles: &mut HashMap<String,Profile>) -> Result<(),String>
+fn remove_profile(name:&String, basename:&Path, profiles: &mut Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
 {
-	let (basename,new_name,org_name) = make_canon_names(basename, name)?;
+	let basename = if is_pattern(basename) {
+		std::path::absolute(basename).map_err(|e|format!(r#"Failed to make "{}" absolute: {e}"#,basename.display()))?
+	} else {
+		canonicalize(basename)?
+	};
 	let profile = profiles.get_mut(name).ok_or(format!(r#"Profile "{name}" doesn't exist"#))?;
 	let found = profile.files.iter().position(|p|p.eq(&basename))
 		.ok_or(format!(r#"File "{}" not found in Profile "{name}""#, basename.display()))?;
 	profile.files.remove(found);
-	std::fs::remove_file(&new_name)
-		.map_err(|e|format!(r#"Failed to remove file "{}": {e}"#, new_name.display()))?;
-	std::fs::remove_file(&org_name)
-		.map_err(|e|format!(r#"Failed to remove file "{}": {e}"#, org_name.display()))?;
+	let files = if is_pattern(&basename) {std::mem::take(&mut profile.matched)} else {vec![basename.clone()]};
 	log::info!(r#"File "{}" removed from Profile "{name}""#,basename.display());
-	if profile.files.is_empty(){
+	let extended = profiles.values().any(|p|p.extends.as_ref() == Some(name));
+	if profiles[name.as_str()].is_empty() && !extended {
 		log::info!(r#"Profile "{name}" is empty now, removing it.."#);
-		profiles.remove(name);
+		profiles.shift_remove(name);
+	}
+	if !profiles.values().any(|p|p.files.contains(&basename)) {
+		state.globs.remove(&basename);
 	}
+	set_matched(profiles, state);
+	forget(&files, std::slice::from_ref(name), profiles, store, state, tx);
 	Ok(())
 }
 
-fn activate(name:&String, profiles: &HashMap<String,Profile>) -> Result<(),String>
+/// Stage removing the variants of `files` for the profiles in `names` that don't manage them anymore,
+/// and everything about the files that aren't managed at all anymore.
+fn forget(files:&[PathBuf], names:&[String], profiles: &Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction)
 {
-	let profile = profiles.get(name).ok_or(format!(r#"Profile "{name}" doesn't exist"#))?;
+	for file in files {
+		for name in names {
+			if !profiles.get(name).is_some_and(|p|p.manages(file)) && store.has(file, name) {
+				store.remove(file, name, tx);
+			}
+		}
+		if !profiles.values().any(|p|p.manages(file)) {
+			store.remove(file, "org", tx);
+			state.files.remove(file);
+			state.attributes.remove(file);
+		}
+	}
+}
+
+/// Fill in what the patterns of all profiles matched when they were last expanded.
+fn set_matched(profiles: &mut Profiles, state: &State)
+{
+	for profile in profiles.values_mut() {
+		let mut matched:Vec<PathBuf> = profile.patterns()
+			.flat_map(|p|state.globs.get(p).into_iter().flatten())
+			.cloned().collect();
+		matched.sort();
+		matched.dedup();
+		profile.matched = matched;
+	}
+}
+
+/// Expand the glob patterns of all profiles.
+///
+/// Files that appeared are managed from now on, with their current content as original and as variant
+/// of each profile with the pattern. Files that disappeared are reported and not managed anymore.
+fn expand_patterns(profiles: &mut Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
+{
+	let mut patterns:Vec<PathBuf> = profiles.values().flat_map(|p|p.patterns()).cloned().collect();
+	patterns.sort();
+	patterns.dedup();
+	state.globs.retain(|p,_|patterns.contains(p));
+	let options = glob::MatchOptions{require_literal_leading_dot:true, ..Default::default()};
+	let mut vanished = vec![];
+	for pattern in patterns {
+		let text = pattern.to_str().ok_or(format!(r#"Pattern "{}" is not valid UTF-8"#,pattern.display()))?;
+		let mut matches = vec![];
+		for path in glob::glob_with(text, options).map_err(|e|format!(r#"Invalid pattern "{text}": {e}"#))? {
+			matches.push(canonicalize(&path.map_err(|e|format!(r#"Failed expanding "{text}": {e}"#))?)?);
+		}
+		// the pattern might match the variants kept next to the files as well
+		let matched = matches.clone();
+		matches.retain(|m|!m.extension().and_then(|e|e.to_str())
+			.is_some_and(|e|(e == "org" || profiles.contains_key(e)) && matched.contains(&m.with_extension(""))));
+
+		let names:Vec<String> = profiles.iter().filter(|(_,p)|p.files.contains(&pattern)).map(|(n,_)|n.clone()).collect();
+		let old = state.globs.get(&pattern).cloned().unwrap_or_default();
+		for file in matches.iter().filter(|f|!old.contains(f)) {
+			let managed = profiles.values().any(|p|p.manages(file));
+			if !managed {
+				store.put(file, "org", file, tx)?;
+				state.files.insert(file.clone(), tree::hash_path(file)?);
+				if file.is_file() {
+					state.attributes.insert(file.clone(), Attributes::read(file)?);
+				}
+			}
+			for name in &names {
+				if store.has(file, name) {
+					continue;
+				}
+				// the file might have a variant of another profile in place
+				if managed {
+					store.copy_variant(file, "org", name, tx)?;
+				} else {
+					store.put(file, name, file, tx)?;
+				}
+			}
+			log::info!(r#"Managing "{}" (matched by "{text}")"#,file.display());
+		}
+		for file in old.into_iter().filter(|f|!matches.contains(f)) {
+			log::warn!(r#""{}" (matched by "{text}") disappeared, it isn't managed anymore"#,file.display());
+			vanished.push((file, names.clone()));
+		}
+		state.globs.insert(pattern, matches);
+		set_matched(profiles, state);
+	}
+	for (file,names) in vanished {
+		forget(&[file], &names, profiles, store, state, tx);
+	}
+	Ok(())
+}
+
+/// The attributes a managed file gets when the variant of `profile` (the original if None) is put in place.
+fn attributes(basename:&Path, profile:Option<&Effective>, state: &mut State) -> Result<Option<Attributes>,String>
+{
+	// files added before attributes were recorded keep the ones they have now
+	if !state.attributes.contains_key(basename) && basename.is_file() && !basename.is_symlink() {
+		state.attributes.insert(basename.to_owned(), Attributes::read(basename)?);
+	}
+	let Some(recorded) = state.attributes.get(basename) else {return Ok(None)};
+	match profile {
+		Some(profile) => recorded.with_overrides(profile.mode(), profile.owner()).map(Some),
+		None => Ok(Some(recorded.clone())),
+	}
+}
+
+/// The variant `variant` of `basename` rendered with the variables of `profile`, if it's a template.
+fn render(basename:&Path, variant:&str, profile:&Effective, store: &Store) -> Result<Option<String>,String>
+{
+	if !profile.is_template(basename) {
+		return Ok(None);
+	}
+	render_file(&store.source(basename, variant)?, profile).map(Some)
+}
+
+/// The template `source` rendered with the variables of `profile`.
+fn render_file(source:&Path, profile:&Effective) -> Result<String,String>
+{
+	let template = std::fs::read_to_string(source)
+		.map_err(|e|format!(r#"Failed reading template "{}": {e}"#,source.display()))?;
+	template::render(&template, &profile.vars(), source)
+}
+
+/// The hash of what activating `profile` puts in place of `basename`.
+fn variant_hash(basename:&Path, profile:&Effective, store: &Store) -> Result<String,String>
+{
+	let variant = profile.variant(basename)
+		.ok_or(format!(r#"File "{}" not found in Profile "{}""#,basename.display(),profile.name))?;
+	match render(basename, variant, profile, store)? {
+		Some(rendered) => Ok(store::hash_bytes(rendered.as_bytes())),
+		None => store.hash(basename, variant),
+	}
+}
+
+/// Check that `names` can be active together (with the active profiles of other groups), and return their groups.
+fn activation_groups(names:&[String], profiles: &Profiles, state: &State) -> Result<Vec<String>,String>
+{
+	let selected = names.iter().map(|n|Effective::new(profiles, n)).collect::<Result<Vec<_>,_>>()?;
+	for (i,profile) in selected.iter().enumerate() {
+		if let Some(other) = selected[..i].iter().find(|o|o.group() == profile.group()) {
+			return Err(format!(r#"Profiles "{}" and "{}" are both in group "{}", only one of them can be active"#,other.name,profile.name,profile.group()));
+		}
+	}
+	let groups:Vec<String> = selected.iter().map(|p|p.group().to_string()).collect();
+	let staying = active_profiles(profiles, state).into_iter()
+		.filter(|p|!groups.iter().any(|g|g == p.group()));
+	let combined:Vec<Effective> = selected.into_iter().chain(staying).collect();
+	for (i,profile) in combined.iter().enumerate() {
+		for (file,_) in profile.files() {
+			if let Some(other) = combined[..i].iter().find(|o|o.manages(file)) {
+				return Err(format!(r#"Profiles "{}" and "{}" both manage "{}", they can't be active at the same time"#,other.name,profile.name,file.display()));
+			}
+		}
+	}
+	Ok(groups)
+}
+
+/// Activate the first profile of each group whose rules match, unless it's active already.
+fn auto(profiles: &Profiles, store: &mut Store, state: &mut State, force:bool, hooks: &mut Pending, tx: &mut Transaction) -> Result<(),String>
+{
+	let mut selected:Vec<Effective> = vec![];
+	for (name,profile) in profiles {
+		let Some(rules) = &profile.auto else {continue};
+		let effective = Effective::new(profiles, name)?;
+		if selected.iter().any(|s|s.group() == effective.group()) {
+			continue;
+		}
+		if rules.matches().map_err(|e|format!(r#"Failed to check the rules of profile "{name}": {e}"#))? {
+			log::info!(r#"Profile "{name}" matches"#);
+			selected.push(effective);
+		}
+	}
+	if selected.is_empty() {
+		log::info!("No profile matches");
+		return Ok(());
+	}
+	let names:Vec<String> = selected.iter()
+		.filter(|p|state.active.get(p.group()).and_then(|a|a.profile.as_deref()) != Some(p.name))
+		.map(|p|p.name.to_string())
+		.collect();
+	if names.is_empty() {
+		log::info!("The matching profiles are already active");
+		return Ok(());
+	}
+	activate_profiles(&names, profiles, store, state, force, hooks, tx)
+}
+
+/// Activate the profiles `names`, de-activating the other profiles of their groups.
+fn activate_profiles(names:&[String], profiles: &Profiles, store: &mut Store, state: &mut State, force:bool, hooks: &mut Pending, tx: &mut Transaction) -> Result<(),String>
+{
+	let groups = activation_groups(names, profiles, state)?;
+	deactivate(Some(&groups), profiles, store, state, force, hooks, tx)?;
+	names.iter().try_for_each(|name|activate(name, profiles, store, state, hooks, tx))
+}
+
+/// Capture (or report) the `files` `watch` saw changing, unless their content is the one last written.
+fn watched_edits(files:&[PathBuf], mode:Watch, profiles: &Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
+{
+	// a file that is replaced might be missing for a moment
+	let existing:Vec<PathBuf> = files.iter().filter(|f|f.symlink_metadata().is_ok()).cloned().collect();
+	let active = active_profiles(profiles, state);
+	let (capture_files,report):(Vec<PathBuf>,Vec<PathBuf>) = find_drifted(existing.iter(), store, state)?.into_iter()
+		.partition(|f|mode == Watch::Capture && !f.is_symlink() && active.iter().any(|p|p.manages(f) && !p.is_template(f)));
+	for file in &report {
+		log::warn!(r#""{}" was modified, commands overwriting it need --force"#, file.display());
+	}
+	if capture_files.is_empty() {
+		return Ok(());
+	}
+	capture(None, capture_files, profiles, store, state, tx)
+}
+
+/// Activate the active profiles again, e.g. after the profiles file changed.
+fn reapply(config: &mut Config, store: &mut Store, state: &mut State, force:bool, hooks: &mut Pending, tx: &mut Transaction) -> Result<(),String>
+{
+	expand_patterns(&mut config.profiles, store, state, tx)?;
+	let names:Vec<String> = active_profiles(&config.profiles, state).iter().map(|p|p.name.to_string()).collect();
+	if names.is_empty() {
+		return Ok(());
+	}
+	log::info!("Applying {} again", names.iter().map(|n|format!(r#""{n}""#)).collect::<Vec<_>>().join(", "));
+	activate_profiles(&names, &config.profiles, store, state, force, hooks, tx)
+}
+
+/// The managed files and directories, as far as they exist.
+fn watched_files(path:&Path) -> Result<Vec<PathBuf>,String>
+{
+	let mut files = vec![];
+	session(path, true, true, |config,_,_,_,_|{
+		files = managed_files(&config.profiles).into_iter().cloned().collect();
+		Ok(())
+	})?;
+	Ok(files)
+}
+
+/// Capture (or report) edits of the managed files, and apply the active profiles again whenever the profiles file changes.
+///
+/// Each change is handled like a separate invocation, holding the lock only while doing so. Runs until SIGTERM or SIGINT.
+fn watch(path:&Path, force:bool) -> Result<(),String>
+{
+	let config_path = std::path::absolute(path).map_err(|e|format!(r#"Failed to make "{}" absolute: {e}"#, path.display()))?;
+	let read_config = ||std::fs::read(&config_path).ok();
+	let mut watcher = watch::Watcher::new()?;
+	let mut files = watched_files(path)?;
+	watcher.watch(files.iter().chain([&config_path]))?;
+	let mut config_content = read_config();
+	log::info!(r#"Watching {} managed files and "{}""#, files.len(), path.display());
+	while let Some(changed) = watcher.wait()? {
+		let edited:Vec<PathBuf> = files.iter().filter(|f|changed.iter().any(|c|c.starts_with(f))).cloned().collect();
+		if !edited.is_empty() {
+			let result = session(path, true, false, |config,store,state,_,tx|
+				watched_edits(&edited, config.settings.watch, &config.profiles, store, state, tx));
+			if let Err(e) = result {
+				log::error!("{e}");
+			}
+		}
+		// also changes when it's saved by a session, but only in what is applied anyway
+		if changed.contains(&config_path) && read_config() != config_content {
+			log::info!(r#""{}" changed"#, path.display());
+			if let Err(e) = session(path, true, false, |config,store,state,hooks,tx|reapply(config, store, state, force, hooks, tx)) {
+				log::error!("{e}");
+			}
+			config_content = read_config();
+			// e.g. a typo while the profiles file is edited, the files watched so far stay watched
+			match watched_files(path) {
+				Ok(new) => files = new,
+				Err(e) => log::error!("{e}"),
+			}
+			watcher.watch(files.iter().chain([&config_path]))?;
+		}
+	}
+	log::info!("Stopped watching");
+	Ok(())
+}
+
+/// How a symlink shows up in diffs.
+fn link_content(target:&Path) -> Vec<u8>
+{
+	format!("symlink to {}\n",target.display()).into_bytes()
+}
+
+fn read(path:&Path) -> Result<Vec<u8>,String>
+{
+	std::fs::read(path).map_err(|e|format!(r#"Failed reading "{}": {e}"#,path.display()))
+}
+
+/// What activating `profile` (de-activating if None) puts in place of `basename`, by path relative to it.
+fn variant_contents(basename:&Path, profile:Option<&Effective>, store: &mut Store) -> Result<BTreeMap<PathBuf,Vec<u8>>,String>
+{
+	let variant = profile.and_then(|p|p.variant(basename)).unwrap_or("org");
+	let mut contents = BTreeMap::new();
+	if let Some(profile) = profile && let Some(rendered) = render(basename, variant, profile, store)? {
+		contents.insert(PathBuf::new(), rendered.into_bytes());
+	} else if store.is_tree(basename) {
+		for (relative,node) in store.tree(basename, variant)? {
+			match node {
+				Node::File(hash) => {contents.insert(relative.clone(), read(&store.tree_file(basename, variant, &relative, &hash))?);}
+				Node::Link(target) => {contents.insert(relative, link_content(&target));}
+				Node::Dir => {}
+			}
+		}
+	} else {
+		contents.insert(PathBuf::new(), read(&store.source(basename, variant)?)?);
+	}
+	Ok(contents)
+}
+
+/// The current content of `basename`, by path relative to it (empty if it doesn't exist).
+fn live_contents(basename:&Path, store: &Store) -> Result<BTreeMap<PathBuf,Vec<u8>>,String>
+{
+	let mut contents = BTreeMap::new();
+	// links to the variants are followed, they are the way its content is put in place
+	if basename.is_symlink() && !store.is_own_link(basename) {
+		let target = std::fs::read_link(basename).map_err(|e|format!(r#"Failed reading "{}": {e}"#,basename.display()))?;
+		contents.insert(PathBuf::new(), link_content(&target));
+	} else if basename.is_dir() {
+		for (relative,node) in tree::read(basename)? {
+			match node {
+				Node::File(_) => {contents.insert(relative.clone(), read(&basename.join(&relative))?);}
+				Node::Link(target) => {contents.insert(relative, link_content(&target));}
+				Node::Dir => {}
+			}
+		}
+	} else if basename.exists() {
+		contents.insert(PathBuf::new(), read(basename)?);
+	}
+	Ok(contents)
+}
+
+/// The profile `name`, or None for "org" (the originals).
+fn profile_or_org<'a>(profiles: &'a Profiles, name:&'a str) -> Result<Option<Effective<'a>>,String>
+{
+	if name == "org" {Ok(None)} else {Effective::new(profiles, name).map(Some)}
+}
+
+fn diff(name:&str, other:&[String], live:bool, color:Color, format:Format, profiles: &Profiles, store: &mut Store) -> Result<(),String>
+{
+	// the first of the other arguments is a file if it doesn't name a profile
+	let (other,files) = match other.split_first() {
+		Some((first,rest)) if first == "org" || profiles.contains_key(first) => (Some(first.as_str()),rest),
+		_ => (None,other),
+	};
+	if live && other.is_some() {
+		return Err("--live compares a profile with the live files, not with another profile".to_string());
+	}
+	// old is what's compared against, new the profile
+	let (old_name,old,new_name,new) = match other {
+		Some(other) => (name, profile_or_org(profiles, name)?, other, profile_or_org(profiles, other)?),
+		None => (if live {"live"} else {"org"}, None, name, profile_or_org(profiles, name)?),
+	};
+	let mut managed:Vec<&PathBuf> = old.iter().chain(new.iter()).flat_map(|p|p.files().into_iter().map(|(f,_)|f)).collect();
+	if old.is_none() && new.is_none() {
+		managed = managed_files(profiles);
+	}
+	managed.sort();
+	managed.dedup();
+	let files:Vec<PathBuf> = if files.is_empty() {
+		managed.into_iter().cloned().collect()
+	} else {
+		files.iter().map(|f|{
+			let basename = canonicalize(Path::new(f))?;
+			if managed.contains(&&basename) {Ok(basename)}
+			else {Err(format!(r#"File "{}" is not managed by "{name}"{}"#, basename.display(), other.map(|o|format!(r#" or "{o}""#)).unwrap_or_default()))}
+		}).collect::<Result<Vec<_>,_>>()?
+	};
+	let colored = format == Format::Text && match color {
+		Color::Auto => std::io::stdout().is_terminal(),
+		Color::Always => true,
+		Color::Never => false,
+	};
+	let mut diffs = vec![];
+	for basename in files {
+		let old_contents = if live {live_contents(&basename, store)?} else {variant_contents(&basename, old.as_ref(), store)?};
+		let new_contents = variant_contents(&basename, new.as_ref(), store)?;
+		let mut paths:Vec<&PathBuf> = old_contents.keys().chain(new_contents.keys()).collect();
+		paths.sort();
+		paths.dedup();
+		for relative in paths {
+			let path = if relative.as_os_str().is_empty() {basename.clone()} else {basename.join(relative)};
+			let old_label = format!("{} ({old_name})",path.display());
+			let new_label = format!("{} ({new_name})",path.display());
+			let old = old_contents.get(relative).map(Vec::as_slice);
+			let new = new_contents.get(relative).map(Vec::as_slice);
+			if let Some(diff) = diff::unified(&old_label, old, &new_label, new, colored) {
+				let binary = old.into_iter().chain(new).any(diff::is_binary);
+				diffs.push(output::FileDiff{path, binary, diff});
+			}
+		}
+	}
+	let report = output::Diff{old:old_name.to_string(), new:new_name.to_string(), files:diffs};
+	output::print(format, &report, |report|{
+		for file in &report.files {
+			print!("{}", file.diff);
+		}
+		if report.files.is_empty() {
+			log::info!("No differences");
+		}
+	})
+}
+
+/// Stage putting `variant` in place of `basename`, with the settings of `profile` (as the original if None).
+fn apply_variant(basename:&Path, variant:&str, profile:Option<&Effective>, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
+{
+	let attributes = attributes(basename, profile, state)?;
+	match profile.map(|p|render(basename, variant, p, store)).transpose()?.flatten() {
+		// always a plain file, whatever the strategy
+		Some(rendered) => {
+			state.files.insert(basename.to_owned(), store::hash_bytes(rendered.as_bytes()));
+			tx.write_with(basename, rendered.into_bytes(), attributes);
+		}
+		None => {
+			store.install(basename, variant, attributes, tx)?;
+			state.files.insert(basename.to_owned(), store.hash(basename, variant)?);
+		}
+	}
+	Ok(())
+}
+
+fn activate(name:&String, profiles: &Profiles, store: &mut Store, state: &mut State, hooks: &mut Pending, tx: &mut Transaction) -> Result<(),String>
+{
+	let profile = Effective::new(profiles, name)?;
 	log::info!(r#"Activating profile "{name}""#);
-	for file in &profile.files
+	hooks.profile(Event::Activate, &profile);
+	for (basename,variant) in profile.files()
 	{
-		let (basename,new_name,_) = make_canon_names(file, name)?;
-		copy_file(&new_name,&basename)?;
+		apply_variant(basename, variant, Some(&profile), store, state, tx)?;
+		hooks.file(Event::Activate, basename, Some(name));
 	}
+	state.active.insert(profile.group().to_string(), Activation::now(Some(name.clone())));
 	Ok(())
 }
-fn deactivate(profiles: &HashMap<String,Profile>) -> Result<(),String>
+/// Reset the files of all profiles of `groups` (of all profiles if None) to their originals.
+///
+/// Files managed by the active profiles of other groups are left alone.
+fn deactivate(groups:Option<&[String]>, profiles: &Profiles, store: &mut Store, state: &mut State, force:bool, hooks: &mut Pending, tx: &mut Transaction) -> Result<(),String>
 {
-	log::info!("Deactivating all profiles ...");
-	let files:std::collections::HashSet<_> = profiles.iter().map(|(_,p)|p.files.iter()).flatten().collect();
-	for file in files
+	let all:Vec<Effective> = profiles.keys().filter_map(|n|Effective::new(profiles, n).ok()).collect();
+	let groups:Vec<String> = match groups {
+		Some(groups) => {
+			if let Some(unknown) = groups.iter().find(|g|!all.iter().any(|p|p.group() == g.as_str())) {
+				return Err(format!(r#"No profile is in group "{unknown}""#));
+			}
+			log::info!("Deactivating all profiles of {} ...",groups.iter().map(|g|format!(r#"group "{g}""#)).collect::<Vec<_>>().join(", "));
+			groups.to_vec()
+		}
+		None => {
+			log::info!("Deactivating all profiles ...");
+			all.iter().map(|p|p.group().to_string()).chain(state.active.keys().cloned()).collect()
+		}
+	};
+	let others:Vec<Effective> = active_profiles(profiles, state).into_iter()
+		.filter(|p|!groups.iter().any(|g|g == p.group()))
+		.collect();
+	let mut files:Vec<&PathBuf> = all.iter()
+		.filter(|p|groups.iter().any(|g|g == p.group()))
+		.flat_map(|p|p.files().into_iter().map(|(f,_)|f))
+		.filter(|f|!others.iter().any(|o|o.manages(f)))
+		.collect();
+	files.sort();
+	files.dedup();
+	check_drift(find_drifted(files.iter().copied(), store, state)?, force)?;
+	for profile in active_profiles(profiles, state).iter().filter(|p|groups.iter().any(|g|g == p.group())) {
+		hooks.profile(Event::Deactivate, profile);
+	}
+	for basename in files
 	{
-		let (basename,_,org_name) = make_canon_names(file, "org")?;
-		copy_file(&org_name,&basename)?;
+		apply_variant(basename, "org", None, store, state, tx)?;
+		hooks.file(Event::Deactivate, basename, None);
+	}
+	// a profile might have been moved to another group since it was activated
+	state.active.retain(|_,a|!a.profile.as_ref()
+		.is_some_and(|name|all.iter().any(|p|p.name == name && groups.iter().any(|g|g == p.group()))));
+	for group in groups {
+		state.active.insert(group, Activation::now(None));
+	}
+	Ok(())
+}
+
+fn capture(name:Option<String>, mut files:Vec<PathBuf>, profiles: &Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
+{
+	// the first argument is a file if it doesn't name a profile
+	let name = match name {
+		Some(n) if n == "org" || profiles.contains_key(&n) => Some(n),
+		Some(n) => {files.insert(0,n.into());None},
+		None => None,
+	};
+	let active = active_profiles(profiles, state);
+	// the profiles the files are captured for, inherited files are captured into the variant of the profile they are inherited from
+	let (targets,managed):(Vec<Effective>,Vec<(&PathBuf,&str)>) = match name.as_deref() {
+		Some("org") => (vec![], managed_files(profiles).into_iter().map(|f|(f,"org")).collect()),
+		Some(name) => {
+			let profile = Effective::new(profiles, name)?;
+			let files = profile.files();
+			(vec![profile], files)
+		}
+		None => {
+			let files = managed_files(profiles).into_iter().map(|f|(f,live_variant(f, &active))).collect();
+			(active_profiles(profiles, state), files)
+		}
+	};
+	let files:Vec<(PathBuf,&str)> = if files.is_empty() {
+		managed.into_iter().map(|(f,v)|(f.clone(),v)).collect()
+	} else {
+		files.iter().map(|f|{
+			let basename = canonicalize(f)?;
+			match managed.iter().find(|(m,_)|**m == basename) {
+				Some((_,variant)) => Ok((basename,*variant)),
+				None => Err(match &name {
+					Some(name) => format!(r#"File "{}" not found in Profile "{name}""#, basename.display()),
+					None => format!(r#"File "{}" is not managed"#, basename.display()),
+				}),
+			}
+		}).collect::<Result<Vec<_>,_>>()?
+	};
+	for (file,variant) in &files {
+		if let Some(profile) = targets.iter().find(|p|p.variant(file) == Some(variant) && p.is_template(file)) {
+			return Err(format!(r#""{}" is a template in profile "{}", edit its variant "{}" instead"#,file.display(),profile.name,store.source(file, variant)?.display()));
+		}
+	}
+	for (basename,variant) in files {
+		store.put(&basename, variant, &basename, tx)?;
+		if live_variant(&basename, &active) == variant {
+			state.files.insert(basename.clone(), tree::hash_path(&basename)?);
+		}
+		if variant == "org" {
+			if basename.is_file() {
+				state.attributes.insert(basename.clone(), Attributes::read(&basename)?);
+			}
+			log::info!(r#"Captured "{}" as original"#,basename.display());
+		} else {
+			log::info!(r#"Captured "{}" into profile "{variant}""#,basename.display());
+		}
+	}
+	Ok(())
+}
+
+/// Run a shell command with `path` as its argument (replacing "{}", or appended).
+fn run_shell(command:&str, path:&Path) -> Result<std::process::ExitStatus,String>
+{
+	let command = if command.contains("{}") {command.replace("{}", r#""$1""#)} else {format!(r#"{command} "$1""#)};
+	std::process::Command::new("sh").arg("-c").arg(&command).arg("sh").arg(path)
+		.status().map_err(|e|format!(r#"Failed to run "{command}": {e}"#))
+}
+
+/// Create a new temporary directory only the current user can access.
+///
+/// It never reuses an existing directory, which could have been prepared by someone else.
+fn private_temp_dir() -> Result<PathBuf,String>
+{
+	use std::os::unix::fs::DirBuilderExt;
+	let nanos = jiff::Timestamp::now().subsec_nanosecond();
+	for attempt in 0.. {
+		let dir = std::env::temp_dir().join(format!("profile-rs.{}.{nanos}.{attempt}", std::process::id()));
+		match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+			Ok(()) => return Ok(dir),
+			Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 100 => continue,
+			Err(e) => return Err(format!(r#"Failed to create directory "{}": {e}"#, dir.display())),
+		}
+	}
+	unreachable!()
+}
+
+/// A variant edited in a copy, waiting to be saved.
+struct Edited {
+	basename:PathBuf,
+	variant:String,
+	/// the edited copy, in the temporary directory dir
+	path:PathBuf,
+	dir:PathBuf,
+}
+
+/// Let the user edit a copy of the variant of `file` of profile `name`, and check it.
+///
+/// Returns None if it wasn't changed.
+fn edit(name:&str, file:&Path, validate:Option<String>, config: &Config, store: &Store, state: &State, force:bool) -> Result<Option<Edited>,String>
+{
+	let profiles = &config.profiles;
+	let basename = canonicalize(file)?;
+	let profile = profile_or_org(profiles, name)?;
+	let variant = match &profile {
+		Some(profile) => profile.variant(&basename),
+		None => managed_files(profiles).contains(&&basename).then_some("org"),
+	}.ok_or(format!(r#"File "{}" not found in Profile "{name}""#, basename.display()))?;
+	if store.is_tree(&basename) {
+		return Err(format!(r#"Can't edit "{}", it's a managed directory"#, basename.display()));
+	}
+	if variant != name {
+		log::info!(r#""{}" is inherited, editing the variant of profile "{variant}""#, basename.display());
+	}
+	if live_variant(&basename, &active_profiles(profiles, state)) == variant {
+		// it's overwritten with the edited variant
+		check_drift(find_drifted(std::iter::once(&basename), store, state)?, force)?;
+	}
+
+	// the variant is edited as a copy (with the same name, for syntax highlighting), as the store might share its content
+	let dir = private_temp_dir()?;
+	let edited = dir.join(basename.file_name().unwrap_or_default());
+	let source = store.source(&basename, variant)?;
+	atomic::copy(&source, &edited, None)
+		.map_err(|e|format!(r#"Error copying "{}" to "{}": {e}"#, source.display(), edited.display()))?;
+	let editor = std::env::var("VISUAL").or_else(|_|std::env::var("EDITOR")).unwrap_or("vi".to_string());
+	let status = run_shell(&editor, &edited)?;
+	if !status.success() {
+		return Err(format!(r#"Editor "{editor}" failed ({status}), the edited version is kept in "{}""#, edited.display()));
+	}
+	if read(&edited)? == read(&source)? {
+		log::info!(r#""{}" wasn't changed"#, basename.display());
+		std::fs::remove_dir_all(&dir).map_err(|e|format!(r#"Failed to remove "{}": {e}"#, dir.display()))?;
+		return Ok(None);
+	}
+
+	if let Some(command) = validate.or_else(||config.settings.files.get(&basename).and_then(|f|f.validate.clone())) {
+		// templates are checked as rendered
+		let checked = match &profile {
+			Some(profile) if profile.is_template(&basename) => {
+				let rendered = dir.join("rendered").join(basename.file_name().unwrap_or_default());
+				std::fs::create_dir_all(dir.join("rendered"))
+					.map_err(|e|format!(r#"Failed to create directory "{}": {e}"#, dir.display()))?;
+				atomic::write(&rendered, render_file(&edited, profile)?.as_bytes(), None)
+					.map_err(|e|format!(r#"Failed writing "{}": {e}"#, rendered.display()))?;
+				rendered
+			}
+			_ => edited.clone(),
+		};
+		let status = run_shell(&command, &checked)?;
+		if !status.success() {
+			return Err(format!(r#"Validation "{command}" failed ({status}), the edited version is kept in "{}""#, edited.display()));
+		}
+	}
+
+	Ok(Some(Edited{basename, variant:variant.to_string(), path:edited, dir}))
+}
+
+/// Stage saving an edited variant, and putting it in place if it's active.
+fn save_edited(edited:Edited, profiles: &Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
+{
+	let Edited{basename,variant,path,dir} = edited;
+	store.put(&basename, &variant, &path, tx)?;
+	let active = active_profiles(profiles, state);
+	if live_variant(&basename, &active) == variant {
+		let profile = active.iter().find(|p|p.variant(&basename) == Some(&variant));
+		match profile.filter(|p|p.is_template(&basename)) {
+			// the variant in the store is only written when the transaction is applied
+			Some(profile) => {
+				let rendered = render_file(&path, profile)?;
+				let attributes = attributes(&basename, Some(profile), state)?;
+				state.files.insert(basename.clone(), store::hash_bytes(rendered.as_bytes()));
+				tx.write_with(&basename, rendered.into_bytes(), attributes);
+			}
+			None => apply_variant(&basename, &variant, profile, store, state, tx)?,
+		}
+	}
+	// only removed once it was copied into the store, so it's kept if anything fails
+	tree::remove(&dir, &tree::read(&dir)?, tx);
+	log::info!(r#"Saved "{}" into profile "{variant}""#, basename.display());
+	Ok(())
+}
+
+/// All variants ("org" and profile names) of all managed files
+fn all_variants(profiles: &Profiles) -> Vec<(PathBuf,String)>
+{
+	managed_files(profiles).into_iter().flat_map(|file|{
+		let variants = profiles.iter()
+			.filter(|(_,p)|p.manages(file))
+			.map(|(name,_)|name.clone());
+		std::iter::once("org".to_string()).chain(variants)
+			.map(|v|(file.clone(),v))
+	}).collect()
+}
+
+fn migrate_store(profiles: &Profiles, store: &mut Store, tx: &mut Transaction) -> Result<(),String>
+{
+	let dir = store.dir().ok_or("No store directory is configured in the settings")?;
+	log::info!(r#"Moving originals and variants into "{}""#,dir.display());
+	for (file,variant) in all_variants(profiles) {
+		// copies might be next to the file, or in the store directory as plain files when switching to the content backend
+		let current = store.source(&file, &variant).ok();
+		let legacy = [Store::sibling(&file, &variant), store.variant_path(&file, &variant)].into_iter()
+			.find(|p|p.exists() && Some(p) != current.as_ref());
+		if let Some(from) = legacy {
+			store.put(&file, &variant, &from, tx)?;
+			if from.is_dir() {
+				tree::remove(&from, &tree::read(&from)?, tx);
+			} else {
+				tx.remove(&from);
+			}
+		} else if !store.has(&file, &variant) {
+			log::warn!(r#"Neither "{}" nor its copy in the store exist"#,Store::sibling(&file, &variant).display());
+		}
+	}
+	Ok(())
+}
+
+fn list_profiles(profiles: &Profiles, state: &State, format:Format) -> Result<(),String>
+{
+	let listed = profiles.iter().map(|(name,p)|{
+		let profile = Effective::new(profiles, name)?;
+		Ok(output::ListedProfile{
+			name:name.clone(),
+			extends:p.extends.clone(),
+			group:profile.group().to_string(),
+			active:state.active_profiles().any(|a|a == name),
+			files:profile.files().into_iter().map(|(f,_)|f.clone()).collect(),
+		})
+	}).collect::<Result<Vec<_>,String>>()?;
+	output::print(format, &output::ProfileList{profiles:listed}, |list|{
+		for profile in &list.profiles {
+			let count = profile.files.len();
+			let mut details = vec![format!("{count} file{}", if count == 1 {""} else {"s"})];
+			if let Some(base) = &profile.extends {
+				details.push(format!(r#"extends "{base}""#));
+			}
+			if profile.group != DEFAULT_GROUP {
+				details.push(format!(r#"group "{}""#, profile.group));
+			}
+			if profile.active {
+				details.push("active".to_string());
+			}
+			println!("{} ({})", profile.name, details.join(", "));
+		}
+	})
+}
+
+/// Stage putting the variants of the active profiles that use variants of `name` in place again.
+///
+/// Needed after its variants moved, links to them would point nowhere otherwise.
+fn reapply_variants(name:&str, profiles: &Profiles, store: &mut Store, state: &mut State, tx: &mut Transaction) -> Result<(),String>
+{
+	for profile in active_profiles(profiles, state) {
+		for (basename,variant) in profile.files().into_iter().filter(|(_,v)|*v == name) {
+			apply_variant(basename, variant, Some(&profile), store, state, tx)?;
+		}
 	}
 	Ok(())
 }
 
-fn list(name:&String, profiles: &HashMap<String,Profile>) -> Result<(),String>
+fn rename_profile(name:&str, new_name:&str, config: &mut Config, store: &mut Store, state: &mut State, force:bool, tx: &mut Transaction) -> Result<(),String>
 {
+	let profiles = &config.profiles;
+	check_name(new_name)?;
+	if profiles.contains_key(new_name) {
+		return Err(format!(r#"Profile "{new_name}" already exists"#));
+	}
 	let profile = profiles.get(name).ok_or(format!(r#"Profile "{name}" doesn't exist"#))?;
-	for file in &profile.files{
-		println!("{}", file.display());
+	// the live files might be links to the variants
+	let files:Vec<PathBuf> = active_profiles(profiles, state).iter()
+		.flat_map(|p|p.files()).filter(|(_,v)|*v == name).map(|(f,_)|f.clone()).collect();
+	check_drift(find_drifted(files.iter(), store, state)?, force)?;
+
+	for basename in profile.managed() {
+		store.copy_variant(basename, name, new_name, tx)?;
+		store.remove(basename, name, tx);
+	}
+	config.rename_profile(name, new_name);
+	let profiles = &mut config.profiles;
+	for profile in profiles.values_mut().filter(|p|p.extends.as_deref() == Some(name)) {
+		profile.extends = Some(new_name.to_string());
+	}
+	for activation in state.active.values_mut().filter(|a|a.profile.as_deref() == Some(name)) {
+		activation.profile = Some(new_name.to_string());
+	}
+	reapply_variants(new_name, profiles, store, state, tx)?;
+	log::info!(r#"Renamed profile "{name}" to "{new_name}""#);
+	Ok(())
+}
+
+fn clone_profile(name:&str, new_name:&str, profiles: &mut Profiles, store: &mut Store, tx: &mut Transaction) -> Result<(),String>
+{
+	check_name(new_name)?;
+	if profiles.contains_key(new_name) {
+		return Err(format!(r#"Profile "{new_name}" already exists"#));
 	}
+	let profile = profiles.get(name).ok_or(format!(r#"Profile "{name}" doesn't exist"#))?.clone();
+	for basename in profile.managed() {
+		store.copy_variant(basename, name, new_name, tx)?;
+	}
+	profiles.insert(new_name.to_string(), profile);
+	log::info!(r#"Cloned profile "{name}" as "{new_name}""#);
 	Ok(())
 }
 
+fn delete_profile(name:&str, profiles: &mut Profiles, store: &mut Store, state: &mut State, force:bool, hooks: &mut Pending, tx: &mut Transaction) -> Result<(),String>
+{
+	let profile = Effective::new(profiles, name)?;
+	if let Some((derived,_)) = profiles.iter().find(|(_,p)|p.extends.as_deref() == Some(name)) {
+		return Err(format!(r#"Profile "{name}" can't be deleted, "{derived}" extends it"#));
+	}
+	if state.active_profiles().any(|a|a == name) {
+		deactivate(Some(&[profile.group().to_string()]), profiles, store, state, force, hooks, tx)?;
+	}
+	let files:Vec<PathBuf> = profiles[name].managed().cloned().collect();
+	profiles.shift_remove(name);
+	state.globs.retain(|pattern,_|profiles.values().any(|p|p.files.contains(pattern)));
+	forget(&files, &[name.to_string()], profiles, store, state, tx);
+	log::info!(r#"Deleted profile "{name}""#);
+	Ok(())
+}
+
+fn list(name:&str, profiles: &Profiles, store: &Store, format:Format) -> Result<(),String>
+{
+	let profile = Effective::new(profiles, name)?;
+	let files = profile.files().into_iter().map(|(path,variant)|Ok(output::ListedFile{
+		path:path.clone(),
+		variant:variant.to_string(),
+		source:store.source(path, variant)?,
+		hash:variant_hash(path, &profile, store)?,
+	})).collect::<Result<Vec<_>,String>>()?;
+	output::print(format, &output::List{profile:name.to_string(), files}, |list|{
+		for file in &list.files {
+			println!("{}", file.path.display());
+		}
+	})
+}
+
+fn status(profiles: &Profiles, store: &Store, state: &State, format:Format) -> Result<(),String>
+{
+	let activations = state.active.iter().map(|(group,a)|output::GroupActivation{
+		group:group.clone(), profile:a.profile.clone(), since:a.since, by:a.by.clone(),
+	}).collect();
+	let active = active_profiles(profiles, state);
+	let mut files = vec![];
+	for basename in managed_files(profiles) {
+		// also for dangling symlinks
+		let hash = basename.exists().then(||tree::hash_path(basename)).transpose()?;
+		let profile = match (active.iter().find(|p|p.manages(basename)), &hash) {
+			(Some(profile),Some(hash)) if *hash == variant_hash(basename, profile, store)? => Some(profile.name.to_string()),
+			_ => None,
+		};
+		let matches = match (&profile,&hash) {
+			(Some(_),_) => "profile",
+			(None,None) => "missing",
+			(None,Some(hash)) if *hash == store.hash(basename, "org")? => "original",
+			(None,Some(_)) => "modified",
+		};
+		files.push(output::FileStatus{
+			path:basename.clone(),
+			matches:matches.to_string(),
+			profile,
+			symlink:basename.is_symlink(),
+			drifted:!find_drifted(std::iter::once(basename), store, state)?.is_empty(),
+			hash,
+		});
+	}
+	output::print(format, &output::Status{active:activations, files}, |status|{
+		if status.active.is_empty() {
+			println!("No active profile recorded");
+		}
+		for activation in &status.active {
+			let group = if activation.group == DEFAULT_GROUP {String::new()} else {format!(r#" in group "{}""#, activation.group)};
+			let since = activation.since.to_zoned(jiff::tz::TimeZone::system()).strftime("%F %T %Z");
+			let by = &activation.by;
+			match &activation.profile {
+				Some(name) => println!(r#"Active profile{group}: "{name}" (since {since} by {by})"#),
+				None => println!("No active profile{group} (de-activated {since} by {by})"),
+			}
+		}
+		for file in &status.files {
+			let matches = match &file.profile {
+				Some(name) => format!(r#"profile "{name}""#),
+				None => file.matches.clone(),
+			};
+			let link = if file.symlink {" (symlink)"} else {""};
+			println!("{}: {matches}{link}", file.path.display());
+		}
+	})
+}
+
+/// Run `command` on the profiles file `path` and its store and state, and apply the changes it staged.
+///
+/// The changes are applied all-or-nothing, with the hooks of what was (de-)activated around them.
+/// Run `command` on the loaded profiles file, store and state, and apply the changes it staged.
+///
+/// With `read_only` it only takes a shared lock and nothing is saved.
+fn session(path:&Path, wait:bool, read_only:bool, command:impl FnOnce(&mut Config, &mut Store, &mut State, &mut Pending, &mut Transaction) -> Result<(),String>) -> Result<(),String>
+{
+	let _lock = if read_only {
+		let lock = lock::Lock::acquire_shared(path, wait)?;
+		if journal::journal_path(path).exists() {
+			// rolling the interrupted run back changes files
+			drop(lock);
+			lock::Lock::acquire(path, wait)?
+		} else {
+			lock
+		}
+	} else {
+		lock::Lock::acquire(path, wait)?
+	};
+	// an interrupted earlier run might have left files in an undefined state
+	journal::recover(path)?;
+	let mut config = Config::load(path)?;
+	let mut store = Store::new(&config.settings, path)?;
+	let mut state = State::load(path)?;
+	set_matched(&mut config.profiles, &state);
+
+	// all file modifications are staged in tx, and only applied if the command succeeded
+	let mut tx = Transaction::new(journal::journal_path(path));
+	// the hooks of what is (de-)activated, run around applying tx
+	let mut hooks = Pending::new(&config.settings, &state);
+	command(&mut config, &mut store, &mut state, &mut hooks, &mut tx)?;
+	if read_only {
+		return Ok(());
+	}
+	store.save(&mut tx)?;
+	state.save(path, &mut tx)?;
+	config.save(path, &mut tx)?;
+	hooks.run_pre(path, &state)?;
+	tx.apply()?;
+	tx.commit();
+	hooks.run_post(path, &state)
+}
+
 fn main() {
 	let args = Cli::parse();
 	// Initialize logging with the flags from clap
@@ -171,36 +1248,53 @@ fn main() {
         LevelFilter::Info
     );
 
-	let mut profiles = match get_profiles(&args.config)
+	let result = match args.command
 	{
-		Ok(prf) => prf,
-		Err(e) => {log::error!("{e}");exit(1);}
+		Commands::Watch => watch(&args.config, args.force),
+		command => {
+			let read_only = matches!(command, Commands::List{..} | Commands::Profiles | Commands::Status | Commands::Diff{..} | Commands::Verify);
+			let wait = args.wait || read_only && !args.no_wait;
+			session(&args.config, wait, read_only, |config,store,state,hooks,tx| match command
+			{
+				Commands::Add { profile,file } =>
+					expand_patterns(&mut config.profiles,store,state,tx)
+						.and_then(|_|deactivate(None,&config.profiles,store,state,args.force,hooks,tx))
+						.and_then(|_|add_profiles(&profile,&file,&mut config.profiles,store,state,tx))
+						// picks up the files of added patterns
+						.and_then(|_|expand_patterns(&mut config.profiles,store,state,tx)),
+				Commands::Remove { profile,file } =>
+					deactivate(None,&config.profiles,store,state,args.force,hooks,tx).and_then(|_|remove_profiles(&profile,&file,&mut config.profiles,store,state,tx)),
+				Commands::Activate { profile } =>
+					expand_patterns(&mut config.profiles,store,state,tx)
+						.and_then(|_|activate_profiles(&profile,&config.profiles,store,state,args.force,hooks,tx)),
+				Commands::Auto =>
+					expand_patterns(&mut config.profiles,store,state,tx)
+						.and_then(|_|auto(&config.profiles,store,state,args.force,hooks,tx)),
+				Commands::DeActivate { group } => {
+					let groups = (!group.is_empty()).then_some(group.as_slice());
+					deactivate(groups,&config.profiles,store,state,args.force,hooks,tx)
+				}
+				Commands::List { profile } => list(&profile, &config.profiles, store, args.format),
+				Commands::Profiles => list_profiles(&config.profiles, state, args.format),
+				Commands::Rename { profile,new_name } => rename_profile(&profile, &new_name, config, store, state, args.force, tx),
+				Commands::Clone { profile,new_name } => clone_profile(&profile, &new_name, &mut config.profiles, store, tx),
+				Commands::Delete { profile } => delete_profile(&profile, &mut config.profiles, store, state, args.force, hooks, tx),
+				Commands::Status => status(&config.profiles, store, state, args.format),
+				Commands::Diff { profile,other,live,color } => diff(&profile, &other, live, color, args.format, &config.profiles, store),
+				Commands::Edit { profile,file,validate } =>
+					edit(&profile, &file, validate, config, store, state, args.force)
+						.and_then(|edited|edited.map_or(Ok(()), |e|save_edited(e, &config.profiles, store, state, tx))),
+				Commands::MigrateStore => migrate_store(&config.profiles, store, tx),
+				Commands::Verify => store.verify(&all_variants(&config.profiles)),
+				Commands::Capture { profile,file } => capture(profile, file, &config.profiles, store, state, tx),
+				// runs sessions itself
+				Commands::Watch => unreachable!(),
+			})
+		}
 	};
-
-	if let Err(e) = match args.command
+	if let Err(e) = result
 	{
-		Commands::Add { profile,file } =>
-			deactivate(&profiles).and_then(|_|add_profiles(&profile,&file,&mut profiles)),
-		Commands::Remove { profile,file } =>
-			deactivate(&profiles).and_then(|_|remove_profiles(&profile,&file,&mut profiles)),
-		Commands::Activate { profile } =>
-			deactivate(&profiles).and_then(|_|activate(&profile,&profiles)),
-		Commands::DeActivate => deactivate(&profiles),
-		Commands::List { profile } => list(&profile, &profiles),
-	}{
 		log::error!("{e}");
 		exit(1);
 	}
-
-	let new_cfg= match toml::to_string_pretty(&profiles)
-	{
-		Ok(s) => s,
-		Err(e) => {
-			log::error!("Failed to serialize configuration: {e}");exit(1);
-		}
-	};
-	if let Err(e)=File::create(&args.config).and_then(|mut f|f.write_all(new_cfg.as_bytes()))
-	{
-		log::error!(r#"Failed writing "{}": {e}"#,args.config.display());
-	}
 }
@@ -0,0 +1,113 @@
+//! Output of the commands that only read, as text or in a machine readable format.
+//!
+//! The reports are serialized as they are, so their fields are the documented schema of the JSON
+//! and TOML output. Fields are only ever added, never renamed or removed.
+use std::path::PathBuf;
+use clap::ValueEnum;
+use jiff::Timestamp;
+use serde::Serialize;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub enum Format {
+	/// human readable text
+	Text,
+	Json,
+	Toml,
+}
+
+/// Print `report` in `format`, using `text` for the text format.
+pub fn print<T:Serialize>(format:Format, report:&T, text:impl FnOnce(&T)) -> Result<(),String> {
+	let error = |e:&dyn std::fmt::Display|format!("Failed to serialize output: {e}");
+	match format {
+		Format::Text => text(report),
+		Format::Json => println!("{}", serde_json::to_string_pretty(report).map_err(|e|error(&e))?),
+		Format::Toml => print!("{}", toml::to_string_pretty(report).map_err(|e|error(&e))?),
+	}
+	Ok(())
+}
+
+/// `list`
+#[derive(Serialize)]
+pub struct List {
+	pub profile:String,
+	pub files:Vec<ListedFile>,
+}
+
+#[derive(Serialize)]
+pub struct ListedFile {
+	pub path:PathBuf,
+	/// the profile whose variant is used (differs from the listed profile for inherited files)
+	pub variant:String,
+	/// where the content of the variant is kept
+	pub source:PathBuf,
+	/// hash of what activating the profile puts in place
+	pub hash:String,
+}
+
+/// `profiles`
+#[derive(Serialize)]
+pub struct ProfileList {
+	pub profiles:Vec<ListedProfile>,
+}
+
+#[derive(Serialize)]
+pub struct ListedProfile {
+	pub name:String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub extends:Option<String>,
+	pub group:String,
+	pub active:bool,
+	/// all managed files, including inherited ones
+	pub files:Vec<PathBuf>,
+}
+
+/// `status`
+#[derive(Serialize)]
+pub struct Status {
+	/// the last activation of each group
+	pub active:Vec<GroupActivation>,
+	pub files:Vec<FileStatus>,
+}
+
+#[derive(Serialize)]
+pub struct GroupActivation {
+	pub group:String,
+	/// None if the group was de-activated
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub profile:Option<String>,
+	pub since:Timestamp,
+	pub by:String,
+}
+
+#[derive(Serialize)]
+pub struct FileStatus {
+	pub path:PathBuf,
+	/// "profile" (the active one in `profile`), "original", "modified" or "missing"
+	pub matches:String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub profile:Option<String>,
+	pub symlink:bool,
+	/// hash of the live content (missing if the file is)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub hash:Option<String>,
+	/// changed since it was last written, commands overwriting it need --force
+	pub drifted:bool,
+}
+
+/// `diff`
+#[derive(Serialize)]
+pub struct Diff {
+	/// what is compared: "org", "live" or a profile
+	pub old:String,
+	pub new:String,
+	/// only the files that differ
+	pub files:Vec<FileDiff>,
+}
+
+#[derive(Serialize)]
+pub struct FileDiff {
+	pub path:PathBuf,
+	pub binary:bool,
+	/// the unified diff ("Binary files ... differ" for binary files)
+	pub diff:String,
+}
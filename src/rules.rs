@@ -0,0 +1,108 @@
+//! Rules deciding which profile `auto` activates.
+use std::ffi::CStr;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use glob::Pattern;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+/// Conditions a profile is selected on, all of them have to match.
+///
+/// Values are glob patterns (`*`, `?`, `[...]`).
+#[derive(Deserialize,Serialize,Debug,Default,Clone,PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub hostname:Option<String>,
+	/// name of the user running the command
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub user:Option<String>,
+	/// the current working directory
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub cwd:Option<String>,
+	/// environment variables and their values (variables that aren't set never match)
+	#[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+	pub env:IndexMap<String,String>,
+	/// a file that has to exist (or a pattern matching at least one)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub exists:Option<PathBuf>,
+	/// shell command that has to succeed
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub command:Option<String>,
+	/// what the output of command has to be (leading and trailing whitespace is ignored)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub output:Option<String>,
+}
+
+fn pattern(text:&str) -> Result<Pattern,String> {
+	Pattern::new(text).map_err(|e|format!(r#"Invalid pattern "{text}": {e}"#))
+}
+
+fn matches(text:&str, value:&str) -> Result<bool,String> {
+	Ok(pattern(text)?.matches(value))
+}
+
+fn hostname() -> Result<String,String> {
+	let mut buffer = [0u8;256];
+	// SAFETY: the length passed is the size of the buffer, which is zeroed so the name is terminated
+	if unsafe {libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()-1)} != 0 {
+		return Err(format!("Failed to get the hostname: {}", std::io::Error::last_os_error()));
+	}
+	Ok(CStr::from_bytes_until_nul(&buffer).unwrap().to_string_lossy().into_owned())
+}
+
+fn user() -> Result<String,String> {
+	// SAFETY: the result is only read before the next call
+	let passwd = unsafe {libc::getpwuid(libc::getuid())};
+	if passwd.is_null() {
+		return Err("Failed to get the name of the current user".to_string());
+	}
+	Ok(unsafe {CStr::from_ptr((*passwd).pw_name)}.to_string_lossy().into_owned())
+}
+
+impl Rules {
+	/// Whether all conditions match, cheap ones are checked first and the command last.
+	pub fn matches(&self) -> Result<bool,String> {
+		if self.output.is_some() && self.command.is_none() {
+			return Err(r#""output" is only checked together with "command""#.to_string());
+		}
+		if let Some(expected) = &self.hostname && !matches(expected, &hostname()?)? {
+			return Ok(false);
+		}
+		if let Some(expected) = &self.user && !matches(expected, &user()?)? {
+			return Ok(false);
+		}
+		if let Some(cwd) = &self.cwd {
+			let current = std::env::current_dir().map_err(|e|format!("Failed to get the current directory: {e}"))?;
+			if !pattern(cwd)?.matches_path(&current) {
+				return Ok(false);
+			}
+		}
+		for (name,value) in &self.env {
+			let value = pattern(value)?;
+			if !std::env::var(name).is_ok_and(|v|value.matches(&v)) {
+				return Ok(false);
+			}
+		}
+		if let Some(exists) = &self.exists {
+			let text = exists.to_str().ok_or(format!(r#"Pattern "{}" is not valid UTF-8"#,exists.display()))?;
+			let mut found = glob::glob(text).map_err(|e|format!(r#"Invalid pattern "{text}": {e}"#))?;
+			if !found.any(|p|p.is_ok()) {
+				return Ok(false);
+			}
+		}
+		if let Some(command) = &self.command {
+			let output = Command::new("sh").arg("-c").arg(command)
+				.stdin(Stdio::null()).stderr(Stdio::inherit())
+				.output()
+				.map_err(|e|format!(r#"Failed to run "{command}": {e}"#))?;
+			if !output.status.success() {
+				return Ok(false);
+			}
+			if let Some(expected) = &self.output && !matches(expected, String::from_utf8_lossy(&output.stdout).trim())? {
+				return Ok(false);
+			}
+		}
+		Ok(true)
+	}
+}
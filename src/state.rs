@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::read_to_string;
+use std::path::{Path, PathBuf};
+use jiff::Timestamp;
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::attributes::Attributes;
+use crate::transaction::Transaction;
+
+/// Machine local state kept next to the profiles file (as "<profiles file>.state").
+#[derive(Deserialize,Serialize,Debug,Default)]
+pub struct State {
+	/// the last activation of each group of profiles
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty", deserialize_with = "activations")]
+	pub active: BTreeMap<String,Activation>,
+	/// hashes of the content last written to each managed file
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub files: BTreeMap<PathBuf,String>,
+	/// ownership, permissions and extended attributes of the original of each managed file
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub attributes: BTreeMap<PathBuf,Attributes>,
+	/// files each glob pattern matched when it was last expanded
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub globs: BTreeMap<PathBuf,Vec<PathBuf>>,
+	/// the state file as it was read
+	#[serde(skip)]
+	text:String,
+}
+
+/// The last activation (or de-activation if profile is None)
+#[derive(Deserialize,Serialize,Debug,Clone)]
+pub struct Activation {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub profile: Option<String>,
+	pub since: Timestamp,
+	pub by: String,
+}
+
+impl Activation {
+	pub fn now(profile:Option<String>) -> Self {
+		Activation{profile, since:Timestamp::now(), by:current_user()}
+	}
+}
+
+/// State files written before profiles had groups have a single activation.
+fn activations<'de,D:Deserializer<'de>>(deserializer:D) -> Result<BTreeMap<String,Activation>,D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Active {
+		Single(Activation),
+		Groups(BTreeMap<String,Activation>),
+	}
+	Ok(match Active::deserialize(deserializer)? {
+		Active::Single(activation) => BTreeMap::from([(crate::config::DEFAULT_GROUP.to_string(), activation)]),
+		Active::Groups(groups) => groups,
+	})
+}
+
+fn current_user() -> String {
+	if let Ok(user) = std::env::var("SUDO_USER") {
+		return format!("{user} (via sudo)");
+	}
+	std::env::var("USER")
+		.or_else(|_|std::env::var("LOGNAME"))
+		.unwrap_or_else(|_|{
+			use std::os::unix::fs::MetadataExt;
+			std::fs::metadata("/proc/self")
+				.map(|m|format!("uid {}",m.uid()))
+				.unwrap_or("unknown".to_string())
+		})
+}
+
+pub fn state_path(profiles:&Path) -> PathBuf {
+	profiles.with_added_extension("state")
+}
+
+impl State {
+	pub fn load(profiles:&Path) -> Result<State,String> {
+		let path = state_path(profiles);
+		if !path.exists() {
+			return Ok(State::default());
+		}
+		let text = File::open(&path).and_then(read_to_string)
+			.map_err(|e|format!(r#"Failed opening state file "{}":{e}. Aborting."#,path.display()))?;
+		let state:State = toml::from_str(text.as_str())
+			.map_err(|e|format!(r#"Failed parse state file "{}":{e}. Aborting."#,path.display()))?;
+		Ok(State{text, ..state})
+	}
+	/// Stage writing the state file in `tx`, if it changed.
+	pub fn save(&self, profiles:&Path, tx:&mut Transaction) -> Result<(),String> {
+		let path = state_path(profiles);
+		let content = toml::to_string_pretty(self)
+			.map_err(|e|format!("Failed to serialize state: {e}"))?;
+		if content != self.text {
+			tx.write(&path, content.into_bytes());
+		}
+		Ok(())
+	}
+	/// The active profiles of all groups.
+	pub fn active_profiles(&self) -> impl Iterator<Item=&String> {
+		self.active.values().filter_map(|a|a.profile.as_ref())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::MetadataExt;
+	use super::*;
+	use crate::transaction::tests::temp_dir;
+
+	fn save(state:&State, profiles:&Path) {
+		let mut tx = Transaction::new(profiles.with_added_extension("journal"));
+		state.save(profiles, &mut tx).unwrap();
+		tx.apply().unwrap();
+		tx.commit();
+	}
+
+	#[test]
+	fn unchanged_is_not_written() {
+		let dir = temp_dir("state-unchanged");
+		let profiles = dir.join("profiles.toml");
+		let mut state = State::default();
+		state.active.insert("default".to_string(), Activation::now(Some("work".to_string())));
+		state.files.insert("/etc/hosts".into(), "hash".to_string());
+		save(&state, &profiles);
+		let inode = std::fs::metadata(state_path(&profiles)).unwrap().ino();
+		let mut state = State::load(&profiles).unwrap();
+		save(&state, &profiles);
+		assert_eq!(std::fs::metadata(state_path(&profiles)).unwrap().ino(), inode);
+		state.files.insert("/etc/hosts".into(), "changed".to_string());
+		save(&state, &profiles);
+		assert_ne!(std::fs::metadata(state_path(&profiles)).unwrap().ino(), inode);
+		assert_eq!(State::load(&profiles).unwrap().files[Path::new("/etc/hosts")], "changed");
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,506 @@
+//! Where the originals and profile variants of managed files are kept.
+//!
+//! With the "files" backend they are kept as plain copies. By default these are siblings of the
+//! managed file (e.g. "foo.conf.org" and "foo.conf.work"). If a store directory is configured, they
+//! are kept there instead, in a tree mirroring the absolute path of the managed file
+//! (e.g. "<store>/etc/foo.conf.org").
+//!
+//! With the "content" backend every distinct content is kept only once as "<store>/objects/<hash>",
+//! and "<store>/index.toml" records which content each variant of each managed file has.
+//!
+//! Variants of managed directories are complete copies of the tree with the files backend. With the
+//! content backend the [Tree] describing them is kept as an object, as are all files in it.
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::read_to_string;
+use std::path::{Component, Path, PathBuf};
+use sha2::{Digest, Sha256};
+use crate::attributes::Attributes;
+use serde::{Deserialize, Serialize};
+use crate::config::{Backend, Settings, Strategy};
+use crate::transaction::Transaction;
+use crate::tree::{self, Node, Tree};
+
+fn to_hex(hasher:Sha256) -> String {
+	hasher.finalize().iter().map(|b|format!("{b:02x}")).collect()
+}
+
+pub fn hash_file(file:&Path) -> Result<String,String> {
+	let mut hasher = Sha256::new();
+	File::open(file).and_then(|mut f|std::io::copy(&mut f, &mut hasher))
+		.map_err(|e|format!(r#"Failed reading "{}": {e}"#, file.display()))?;
+	Ok(to_hex(hasher))
+}
+
+pub fn hash_bytes(bytes:&[u8]) -> String {
+	to_hex(Sha256::new_with_prefix(bytes))
+}
+
+/// Hashes of the variants of each managed file
+type Variants = BTreeMap<PathBuf,BTreeMap<String,String>>;
+
+#[derive(Deserialize,Serialize,Default)]
+struct IndexFile {
+	/// managed directories, their variants are trees
+	#[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+	trees:BTreeSet<PathBuf>,
+	#[serde(flatten)]
+	variants:Variants,
+}
+
+struct Index {
+	path:PathBuf,
+	variants:Variants,
+	trees:BTreeSet<PathBuf>,
+	/// trees read or created so far, by hash
+	manifests:HashMap<String,Tree>,
+	/// objects referenced when the index was loaded
+	referenced:HashSet<String>,
+	/// objects that will be created by the running transaction
+	pending:HashSet<String>,
+	changed:bool,
+}
+
+/// What is at the place of a managed directory
+enum Live {
+	Missing,
+	/// a symlink or file
+	Other,
+	Dir(Tree),
+}
+
+impl Live {
+	fn read(path:&Path) -> Result<Live,String> {
+		Ok(match path.symlink_metadata() {
+			Err(_) => Live::Missing,
+			Ok(m) if m.is_dir() => Live::Dir(tree::read(path)?),
+			Ok(_) => Live::Other,
+		})
+	}
+}
+
+/// Content put into the files backend by the staged changes
+enum Stored {
+	File(String),
+	Tree(Tree),
+}
+
+pub struct Store {
+	dir:Option<PathBuf>,
+	index:Option<Index>,
+	settings:Settings,
+	/// what managed directories will be like after the changes staged so far
+	staged:HashMap<PathBuf,Live>,
+	/// variants put by the changes staged so far (files backend only), by their path
+	stored:HashMap<PathBuf,Stored>,
+}
+
+impl Store {
+	pub fn new(settings:&Settings, profiles:&Path) -> Result<Store,String> {
+		// a relative store is relative to the profiles file, and made absolute as links point into it
+		let dir = settings.store.as_ref().map(|dir|{
+			let dir = profiles.parent().unwrap_or(Path::new("")).join(dir);
+			std::path::absolute(&dir).map_err(|e|format!(r#"Failed to make "{}" absolute: {e}"#,dir.display()))
+		}).transpose()?;
+		let index = match (settings.backend, &dir) {
+			(Backend::Files, _) => None,
+			(Backend::Content, None) => return Err(r#"The "content" backend needs a store directory"#.to_string()),
+			(Backend::Content, Some(dir)) => {
+				let path = dir.join("index.toml");
+				let file:IndexFile = if path.exists() {
+					File::open(&path).and_then(read_to_string)
+						.map_err(|e|format!(r#"Failed opening store index "{}":{e}. Aborting."#,path.display()))
+						.and_then(|s|
+							toml::from_str(s.as_str()).map_err(|e|format!(r#"Failed parse store index "{}":{e}. Aborting."#,path.display()))
+						)?
+				} else {IndexFile::default()};
+				Some(Index{path, variants:file.variants, trees:file.trees, manifests:HashMap::new(),
+					referenced:HashSet::new(), pending:HashSet::new(), changed:false})
+			}
+		};
+		let mut store = Store{dir, index, settings:settings.clone(), staged:HashMap::new(), stored:HashMap::new()};
+		if let Some(mut index) = store.index.take() {
+			index.referenced = store.referenced(&mut index)?;
+			store.index = Some(index);
+		}
+		Ok(store)
+	}
+
+	pub fn dir(&self) -> Option<&Path> {
+		self.dir.as_deref()
+	}
+
+	/// The variant ("org" or a profile name) of `basename` as it's kept next to it.
+	pub fn sibling(basename:&Path, variant:&str) -> PathBuf {
+		basename.with_added_extension(variant)
+	}
+
+	/// Where the files backend keeps the variant ("org" or a profile name) of the managed file `basename`.
+	pub fn variant_path(&self, basename:&Path, variant:&str) -> PathBuf {
+		match &self.dir {
+			Some(dir) => {
+				let relative:PathBuf = basename.components()
+					.filter(|c|matches!(c, Component::Normal(_)))
+					.collect();
+				dir.join(relative).with_added_extension(variant)
+			}
+			None => Self::sibling(basename, variant),
+		}
+	}
+
+	fn object_path(&self, hash:&str) -> PathBuf {
+		let dir = self.dir.as_ref().expect("content backend without store directory");
+		dir.join("objects").join(&hash[..2]).join(&hash[2..])
+	}
+
+	/// The tree with the given hash, read from its object if it's not known yet.
+	fn manifest<'a>(&self, index:&'a mut Index, hash:&str) -> Result<&'a Tree,String> {
+		if !index.manifests.contains_key(hash) {
+			let path = self.object_path(hash);
+			let tree = File::open(&path).and_then(read_to_string)
+				.map_err(|e|format!(r#"Failed reading "{}": {e}"#,path.display()))
+				.and_then(|s|tree::parse(&s, &path))?;
+			index.manifests.insert(hash.to_string(), tree);
+		}
+		Ok(&index.manifests[hash])
+	}
+
+	/// All objects used by the variants in `index`.
+	fn referenced(&self, index:&mut Index) -> Result<HashSet<String>,String> {
+		let mut referenced = HashSet::new();
+		let variants:Vec<(PathBuf,String)> = index.variants.iter()
+			.flat_map(|(b,v)|v.values().map(|h|(b.clone(),h.clone())))
+			.collect();
+		for (basename,hash) in variants {
+			if index.trees.contains(&basename) {
+				let files = self.manifest(index, &hash)?.values().filter_map(|n|match n {
+					Node::File(h) => Some(h.clone()),
+					_ => None,
+				});
+				referenced.extend(files);
+			}
+			referenced.insert(hash);
+		}
+		Ok(referenced)
+	}
+
+	fn hash_of(&self, index:&Index, basename:&Path, variant:&str) -> Result<String,String> {
+		index.variants.get(basename).and_then(|v|v.get(variant)).cloned()
+			.ok_or_else(||match variant {
+				"org" => format!(r#"There is no original of "{}" in the store"#,basename.display()),
+				_ => format!(r#"There is no variant "{variant}" of "{}" in the store"#,basename.display()),
+			})
+	}
+
+	/// Whether `basename` is a managed directory.
+	pub fn is_tree(&self, basename:&Path) -> bool {
+		match &self.index {
+			None => {
+				let path = self.variant_path(basename, "org");
+				match self.stored.get(&path) {
+					Some(stored) => matches!(stored, Stored::Tree(_)),
+					None => path.is_dir(),
+				}
+			}
+			Some(index) => index.trees.contains(basename),
+		}
+	}
+
+	/// The content of a variant of a managed directory.
+	pub fn tree(&mut self, basename:&Path, variant:&str) -> Result<Tree,String> {
+		match self.index.take() {
+			None => {
+				let path = self.variant_path(basename, variant);
+				match self.stored.get(&path) {
+					Some(Stored::Tree(tree)) => Ok(tree.clone()),
+					_ => tree::read(&path),
+				}
+			}
+			Some(mut index) => {
+				let tree = self.hash_of(&index, basename, variant)
+					.and_then(|h|self.manifest(&mut index, &h).cloned());
+				self.index = Some(index);
+				tree
+			}
+		}
+	}
+
+	pub fn has(&self, basename:&Path, variant:&str) -> bool {
+		match &self.index {
+			None => {
+				let path = self.variant_path(basename, variant);
+				self.stored.contains_key(&path) || path.exists()
+			}
+			Some(index) => index.variants.get(basename).is_some_and(|v|v.contains_key(variant)),
+		}
+	}
+
+	/// The file (or directory) holding the content of a variant.
+	pub fn source(&self, basename:&Path, variant:&str) -> Result<PathBuf,String> {
+		match &self.index {
+			None => Ok(self.variant_path(basename, variant)),
+			Some(index) => self.hash_of(index, basename, variant).map(|h|self.object_path(&h)),
+		}
+	}
+
+	/// The file holding the content of the file `relative` (with the given hash) in a variant of a managed directory.
+	pub fn tree_file(&self, basename:&Path, variant:&str, relative:&Path, hash:&str) -> PathBuf {
+		match &self.index {
+			None => self.variant_path(basename, variant).join(relative),
+			Some(_) => self.object_path(hash),
+		}
+	}
+
+	/// The hash of the content of a variant.
+	pub fn hash(&self, basename:&Path, variant:&str) -> Result<String,String> {
+		match &self.index {
+			None => {
+				let path = self.variant_path(basename, variant);
+				match self.stored.get(&path) {
+					Some(Stored::File(hash)) => Ok(hash.clone()),
+					Some(Stored::Tree(tree)) => tree::hash(tree),
+					None => tree::hash_path(&path),
+				}
+			}
+			Some(index) => self.hash_of(index, basename, variant),
+		}
+	}
+
+	/// Stage putting the file `source` at `to`, as `variant` of `basename`.
+	fn install_file(&self, basename:&Path, variant:&str, source:&Path, to:&Path, attributes:Option<Attributes>, tx:&mut Transaction) {
+		match self.settings.strategy(basename) {
+			Strategy::Reflink => tx.reflink(source, to, attributes),
+			// editing the file must not change the original
+			Strategy::Hardlink if variant != "org" => tx.hardlink(source, to),
+			_ => tx.copy_with(source, to, attributes),
+		}
+	}
+
+	/// Stage putting a variant in place of the managed file `basename`, using the configured strategy.
+	///
+	/// Copies get the given attributes, links share them with the variant.
+	/// Managed directories are changed to have exactly the content of the variant.
+	pub fn install(&mut self, basename:&Path, variant:&str, attributes:Option<Attributes>, tx:&mut Transaction) -> Result<(),String> {
+		let tree = self.is_tree(basename);
+		// objects are shared by all variants with the same content, editing a hardlink to one would change them all
+		if self.settings.strategy(basename) == Strategy::Hardlink && variant != "org" && self.index.is_some() {
+			return Err(format!(r#"Can't link "{}": the hardlink strategy needs the "files" backend"#,basename.display()));
+		}
+		// de-activation always restores plain files
+		if self.settings.strategy(basename) == Strategy::Symlink && variant != "org" {
+			if self.index.is_some() {
+				return Err(format!(r#"Can't link "{}": the symlink strategy needs the "files" backend"#,basename.display()));
+			}
+			let source = self.source(basename, variant)?;
+			let target = std::path::absolute(&source)
+				.map_err(|e|format!(r#"Failed to make "{}" absolute: {e}"#,source.display()))?;
+			if tree && let Live::Dir(current) = self.live(basename)? {
+				tree::remove(basename, &current, tx);
+			}
+			tx.symlink(&target, basename);
+			if tree {
+				self.staged.insert(basename.to_owned(), Live::Other);
+			}
+		} else if tree {
+			let target = self.tree(basename, variant)?;
+			let current = match self.live(basename)? {
+				Live::Dir(current) => Some(current),
+				Live::Other => {tx.remove(basename); None}
+				Live::Missing => None,
+			};
+			tree::sync(basename, current.as_ref(), &target, tx, |relative,hash,to,tx|{
+				let source = self.tree_file(basename, variant, relative, hash);
+				self.install_file(basename, variant, &source, to, None, tx);
+			});
+			self.staged.insert(basename.to_owned(), Live::Dir(target));
+		} else {
+			let source = self.source(basename, variant)?;
+			self.install_file(basename, variant, &source, basename, attributes, tx);
+		}
+		Ok(())
+	}
+
+	/// What is at the place of the managed directory `basename` once the staged changes are applied.
+	fn live(&mut self, basename:&Path) -> Result<Live,String> {
+		match self.staged.remove(basename) {
+			Some(live) => Ok(live),
+			None => Live::read(basename),
+		}
+	}
+
+	/// Whether `basename` is a symlink to one of its own variants.
+	pub fn is_own_link(&self, basename:&Path) -> bool {
+		let Ok(target) = std::fs::read_link(basename) else {return false};
+		let target = basename.parent().unwrap_or(Path::new("/")).join(target);
+		// variants are kept as "<name>.<variant>"
+		let prefix = self.variant_path(basename, "org").with_extension("");
+		self.index.is_none() && target.parent() == prefix.parent() &&
+			target.file_name().zip(prefix.file_name())
+				.is_some_and(|(t,p)|t.as_encoded_bytes().strip_prefix(p.as_encoded_bytes()).is_some_and(|r|r.starts_with(b".")))
+	}
+
+	/// Stage storing the current content of `from` as variant of `basename`.
+	pub fn put(&mut self, basename:&Path, variant:&str, from:&Path, tx:&mut Transaction) -> Result<(),String> {
+		if from.is_dir() {
+			return self.put_tree(basename, variant, from, tx);
+		}
+		if self.index.is_none() {
+			let path = self.variant_path(basename, variant);
+			match self.settings.strategy(basename) {
+				Strategy::Reflink => tx.reflink(from, &path, None),
+				_ => tx.copy(from, &path),
+			}
+			self.stored.insert(path, Stored::File(hash_file(from)?));
+			return Ok(());
+		}
+		let hash = hash_file(from)?;
+		self.put_object(basename, &hash, from, tx);
+		self.set_variant(basename, variant, hash);
+		Ok(())
+	}
+
+	/// Stage storing the variant `from` of `basename` as its variant `to` as well.
+	pub fn copy_variant(&mut self, basename:&Path, from:&str, to:&str, tx:&mut Transaction) -> Result<(),String> {
+		match &self.index {
+			None => {
+				let source = self.variant_path(basename, from);
+				self.put(basename, to, &source, tx)
+			}
+			Some(index) => {
+				let hash = self.hash_of(index, basename, from)?;
+				self.set_variant(basename, to, hash);
+				Ok(())
+			}
+		}
+	}
+
+	/// Stage storing the content of the file `from` as object, unless it's already there.
+	fn put_object(&mut self, basename:&Path, hash:&str, from:&Path, tx:&mut Transaction) {
+		let object = self.object_path(hash);
+		let index = self.index.as_mut().unwrap();
+		if !object.exists() && index.pending.insert(hash.to_string()) {
+			match self.settings.strategy(basename) {
+				Strategy::Reflink => tx.reflink(from, &object, None),
+				_ => tx.copy(from, &object),
+			}
+		}
+	}
+
+	fn set_variant(&mut self, basename:&Path, variant:&str, hash:String) {
+		let index = self.index.as_mut().unwrap();
+		index.variants.entry(basename.to_owned()).or_default().insert(variant.to_string(), hash);
+		index.changed = true;
+	}
+
+	/// Stage storing a snapshot of the directory `from` as variant of `basename`.
+	fn put_tree(&mut self, basename:&Path, variant:&str, from:&Path, tx:&mut Transaction) -> Result<(),String> {
+		let target = tree::read(from)?;
+		if self.index.is_none() {
+			let dir = self.variant_path(basename, variant);
+			let current = match Live::read(&dir)? {
+				Live::Dir(current) => Some(current),
+				Live::Other => {tx.remove(&dir); None}
+				Live::Missing => None,
+			};
+			let reflink = self.settings.strategy(basename) == Strategy::Reflink;
+			tree::sync(&dir, current.as_ref(), &target, tx, |relative,_,to,tx|{
+				if reflink {tx.reflink(&from.join(relative), to, None)} else {tx.copy(&from.join(relative), to)}
+			});
+			self.stored.insert(dir, Stored::Tree(target));
+			return Ok(());
+		}
+		for (relative,node) in &target {
+			if let Node::File(hash) = node {
+				self.put_object(basename, hash, &from.join(relative), tx);
+			}
+		}
+		let hash = tree::hash(&target)?;
+		let object = self.object_path(&hash);
+		let index = self.index.as_mut().unwrap();
+		if !object.exists() && index.pending.insert(hash.clone()) {
+			tx.write(&object, tree::serialize(&target)?.into_bytes());
+		}
+		index.manifests.insert(hash.clone(), target);
+		index.trees.insert(basename.to_owned());
+		self.set_variant(basename, variant, hash);
+		Ok(())
+	}
+
+	/// Stage removing a variant of `basename`.
+	pub fn remove(&mut self, basename:&Path, variant:&str, tx:&mut Transaction) {
+		match &mut self.index {
+			None => {
+				let path = self.variant_path(basename, variant);
+				self.stored.remove(&path);
+				match Live::read(&path) {
+					Ok(Live::Dir(tree)) => tree::remove(&path, &tree, tx),
+					_ => tx.remove(&path),
+				}
+			}
+			Some(index) => {
+				if let Some(variants) = index.variants.get_mut(basename) {
+					variants.remove(variant);
+					if variants.is_empty() {
+						index.variants.remove(basename);
+						index.trees.remove(basename);
+					}
+				}
+				index.changed = true;
+			}
+		}
+	}
+
+	/// Stage writing the index and removing objects that aren't referenced anymore.
+	pub fn save(&mut self, tx:&mut Transaction) -> Result<(),String> {
+		let Some(mut index) = self.index.take() else {return Ok(())};
+		let result = self.save_index(&mut index, tx);
+		self.index = Some(index);
+		result
+	}
+
+	fn save_index(&self, index:&mut Index, tx:&mut Transaction) -> Result<(),String> {
+		if !index.changed {
+			return Ok(());
+		}
+		let referenced = self.referenced(index)?;
+		for unused in index.referenced.iter().filter(|h|!referenced.contains(*h)) {
+			log::debug!("Removing unused object {unused}");
+			tx.remove(&self.object_path(unused));
+		}
+		let file = IndexFile{trees:index.trees.clone(), variants:index.variants.clone()};
+		let content = toml::to_string_pretty(&file)
+			.map_err(|e|format!("Failed to serialize store index: {e}"))?;
+		tx.write(&index.path, content.into_bytes());
+		Ok(())
+	}
+
+	/// Check that all variants exist and, for the content backend, that all objects still have the content they are named after.
+	pub fn verify(&mut self, variants:&[(PathBuf,String)]) -> Result<(),String> {
+		let mut broken = vec![];
+		for (basename,variant) in variants {
+			match self.source(basename, variant) {
+				Ok(source) if !source.exists() => broken.push(format!("\n\t{} ({variant}): {} is missing",basename.display(),source.display())),
+				Ok(source) => if let Some(index) = &self.index {
+					let expected = self.hash_of(index, basename, variant)?;
+					let mut objects = vec![(expected, source)];
+					if self.is_tree(basename) {
+						objects.extend(self.tree(basename, variant)?.values().filter_map(|n|match n {
+							Node::File(h) => Some((h.clone(), self.object_path(h))),
+							_ => None,
+						}));
+					}
+					for (expected,object) in objects {
+						if !object.exists() {
+							broken.push(format!("\n\t{} ({variant}): {} is missing",basename.display(),object.display()));
+						} else if hash_file(&object)? != expected {
+							broken.push(format!("\n\t{} ({variant}): {} is corrupted",basename.display(),object.display()));
+						}
+					}
+				},
+				Err(e) => broken.push(format!("\n\t{e}")),
+			}
+		}
+		if broken.is_empty() {Ok(())}
+		else {Err(format!("The store is inconsistent:{}", broken.concat()))}
+	}
+}
@@ -0,0 +1,73 @@
+//! Variants with `{{ name }}` placeholders, filled in from the variables of the profile.
+use std::path::Path;
+use indexmap::IndexMap;
+
+pub type Vars = IndexMap<String,toml::Value>;
+
+fn value(name:&str, vars:&Vars, source:&Path) -> Result<String,String> {
+	match vars.get(name) {
+		Some(toml::Value::String(s)) => Ok(s.clone()),
+		Some(v @ (toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) | toml::Value::Datetime(_))) => Ok(v.to_string()),
+		Some(_) => Err(format!(r#"Variable "{name}" used in "{}" is not a single value"#,source.display())),
+		None => Err(format!(r#"Variable "{name}" used in "{}" is not defined"#,source.display())),
+	}
+}
+
+/// Replace all placeholders in `template` (read from `source`) by the values of `vars`.
+pub fn render(template:&str, vars:&Vars, source:&Path) -> Result<String,String> {
+	let mut rendered = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(start) = rest.find("{{") {
+		rendered.push_str(&rest[..start]);
+		let after = &rest[start+2..];
+		let end = after.find("}}").ok_or_else(||{
+			let line = template[..template.len()-rest.len()+start].matches('\n').count() + 1;
+			format!(r#"Unclosed "{{{{" in line {line} of "{}""#,source.display())
+		})?;
+		rendered.push_str(&value(after[..end].trim(), vars, source)?);
+		rest = &after[end+2..];
+	}
+	rendered.push_str(rest);
+	Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn vars() -> Vars {
+		let mut vars = Vars::new();
+		vars.insert("host".to_string(), "proxy.corp".into());
+		vars.insert("port".to_string(), 3128.into());
+		vars.insert("list".to_string(), toml::Value::Array(vec![]));
+		vars
+	}
+
+	#[test]
+	fn fills_in_placeholders() {
+		let rendered = render("proxy={{host}}:{{ port }}\n{ not a placeholder }\n", &vars(), Path::new("proxy.conf"));
+		assert_eq!(rendered.unwrap(), "proxy=proxy.corp:3128\n{ not a placeholder }\n");
+	}
+
+	#[test]
+	fn undefined_variable() {
+		let error = render("proxy={{ host }}:{{ user }}", &vars(), Path::new("proxy.conf")).unwrap_err();
+		assert_eq!(error, r#"Variable "user" used in "proxy.conf" is not defined"#);
+	}
+
+	#[test]
+	fn not_a_single_value() {
+		let error = render("{{ list }}", &vars(), Path::new("proxy.conf")).unwrap_err();
+		assert_eq!(error, r#"Variable "list" used in "proxy.conf" is not a single value"#);
+	}
+
+	#[test]
+	fn unclosed_placeholder() {
+		let error = render("host={{ host }}\nport={{ port\n", &vars(), Path::new("proxy.conf")).unwrap_err();
+		assert_eq!(error, r#"Unclosed "{{" in line 2 of "proxy.conf""#);
+		let error = render("a\n{{ x", &vars(), Path::new("proxy.conf")).unwrap_err();
+		assert_eq!(error, r#"Unclosed "{{" in line 2 of "proxy.conf""#);
+		let error = render("{{ x", &vars(), Path::new("proxy.conf")).unwrap_err();
+		assert_eq!(error, r#"Unclosed "{{" in line 1 of "proxy.conf""#);
+	}
+}
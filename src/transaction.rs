@@ -0,0 +1,314 @@
+//! All-or-nothing modification of files.
+//!
+//! Changes are staged first and only applied by [Transaction::apply]. Before a file is touched the
+//! first time, its current version is kept as a hidden backup next to it (as a hardlink where
+//! possible, which is free as files are only ever replaced and never modified in place). If any
+//! step fails all touched files are restored from these backups. Removed directories are kept the
+//! same way, by moving them out of the way.
+//!
+//! Which files are touched, and where their backups are, is recorded in a [Journal] before anything
+//! is changed, so an interrupted transaction can be rolled back later by [crate::journal::recover].
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use crate::atomic;
+use crate::attributes::Attributes;
+use crate::journal::{Entry, Journal};
+
+#[derive(Debug)]
+enum Op {
+	Copy{from:PathBuf, to:PathBuf, attributes:Option<Attributes>},
+	Reflink{from:PathBuf, to:PathBuf, attributes:Option<Attributes>},
+	Hardlink{from:PathBuf, to:PathBuf},
+	Write{to:PathBuf, content:Vec<u8>, attributes:Option<Attributes>},
+	Symlink{target:PathBuf, link:PathBuf},
+	Remove{path:PathBuf},
+	CreateDir{path:PathBuf},
+	RemoveDir{path:PathBuf},
+}
+
+impl Op {
+	fn target(&self) -> &Path {
+		match self {
+			Op::Copy{to,..} | Op::Reflink{to,..} | Op::Hardlink{to,..} | Op::Write{to,..} => to,
+			Op::Symlink{link,..} => link,
+			Op::Remove{path} | Op::CreateDir{path} | Op::RemoveDir{path} => path,
+		}
+	}
+	fn is_dir(&self) -> bool {
+		matches!(self, Op::CreateDir{..} | Op::RemoveDir{..})
+	}
+}
+
+#[derive(Debug)]
+pub struct Transaction {
+	journal_path:PathBuf,
+	journal:Journal,
+	ops:Vec<Op>,
+	touched:HashSet<PathBuf>,
+}
+
+fn backup_name(path:&Path) -> PathBuf {
+	let mut name = OsString::from(".");
+	name.push(path.file_name().unwrap_or_default());
+	name.push(format!(".{}.bak", std::process::id()));
+	path.with_file_name(name)
+}
+
+impl Transaction {
+	pub fn new(journal_path:PathBuf) -> Self {
+		Transaction{journal_path, journal:Journal::default(), ops:vec![], touched:HashSet::new()}
+	}
+	/// Stage replacing `to` with a copy of `from`.
+	pub fn copy(&mut self, from:&Path, to:&Path) {
+		self.ops.push(Op::Copy{from:from.to_owned(), to:to.to_owned(), attributes:None});
+	}
+	/// Stage replacing `to` with a copy of `from` that has the given attributes.
+	pub fn copy_with(&mut self, from:&Path, to:&Path, attributes:Option<Attributes>) {
+		self.ops.push(Op::Copy{from:from.to_owned(), to:to.to_owned(), attributes});
+	}
+	/// Stage replacing `to` with a copy-on-write clone of `from` (or a copy where that's not possible).
+	pub fn reflink(&mut self, from:&Path, to:&Path, attributes:Option<Attributes>) {
+		self.ops.push(Op::Reflink{from:from.to_owned(), to:to.to_owned(), attributes});
+	}
+	/// Stage replacing `to` with a hardlink to `from` (or a copy where that's not possible).
+	pub fn hardlink(&mut self, from:&Path, to:&Path) {
+		self.ops.push(Op::Hardlink{from:from.to_owned(), to:to.to_owned()});
+	}
+	/// Stage replacing `to` with the given content, keeping its permissions and owner.
+	///
+	/// If `to` is a symlink the file it points to is replaced, so the link stays.
+	pub fn write(&mut self, to:&Path, content:Vec<u8>) {
+		let to = std::fs::canonicalize(to).unwrap_or_else(|_|to.to_owned());
+		self.ops.push(Op::Write{to, content, attributes:None});
+	}
+	/// Stage replacing `to` with a file with the given content and attributes.
+	pub fn write_with(&mut self, to:&Path, content:Vec<u8>, attributes:Option<Attributes>) {
+		self.ops.push(Op::Write{to:to.to_owned(), content, attributes});
+	}
+	/// Stage replacing `link` with a symlink to `target`.
+	pub fn symlink(&mut self, target:&Path, link:&Path) {
+		self.ops.push(Op::Symlink{target:target.to_owned(), link:link.to_owned()});
+	}
+	/// Stage removing `path`.
+	pub fn remove(&mut self, path:&Path) {
+		self.ops.push(Op::Remove{path:path.to_owned()});
+	}
+	/// Stage creating the directory `path` (and its parents).
+	pub fn create_dir(&mut self, path:&Path) {
+		self.ops.push(Op::CreateDir{path:path.to_owned()});
+	}
+	/// Stage removing the directory `path`, after everything in it was removed.
+	pub fn remove_dir(&mut self, path:&Path) {
+		self.ops.push(Op::RemoveDir{path:path.to_owned()});
+	}
+
+	/// Keep the current version of `path` before it's changed the first time.
+	fn touch(&mut self, path:&Path) -> Result<(),String> {
+		if !self.touched.insert(path.to_owned()) {
+			return Ok(());
+		}
+		let Some(entry) = self.journal.files.iter_mut().find(|e|e.path == path && !e.dir) else {return Ok(())};
+		if entry.backup.is_some() && path.symlink_metadata().is_err() {
+			// it was only reachable through a directory symlink that was replaced by an earlier step
+			entry.backup = None;
+			return self.journal.save(&self.journal_path);
+		}
+		if let Some(backup) = &entry.backup {
+			let _ = std::fs::remove_file(backup);
+			std::fs::hard_link(path, backup)
+				.or_else(|_|atomic::copy(path, backup, None).map(|_|()))
+				.map_err(|e|format!(r#"Failed to create backup of "{}": {e}"#, path.display()))?;
+		}
+		Ok(())
+	}
+
+	fn apply_op(&mut self, op:&Op) -> Result<(),String> {
+		if let Op::Copy{to,..} | Op::Reflink{to,..} | Op::Hardlink{to,..} | Op::Write{to,..} = op && let Some(dir) = to.parent() {
+			std::fs::create_dir_all(dir)
+				.map_err(|e|format!(r#"Failed to create directory "{}": {e}"#, dir.display()))?;
+		}
+		match op {
+			Op::Copy{from,to,attributes} => {
+				log::debug!(r#"Creating "{}" as a copy of "{}""#,to.display(),from.display());
+				self.touch(to)?;
+				atomic::copy(from, to, attributes.as_ref()).map(|_|())
+					.map_err(|e|format!(r#"Error copying "{}" to "{}": {e}"#, from.display(), to.display()))
+			}
+			Op::Reflink{from,to,attributes} => {
+				log::debug!(r#"Creating "{}" as a clone of "{}""#,to.display(),from.display());
+				self.touch(to)?;
+				atomic::reflink(from, to, attributes.as_ref())
+					.map_err(|e|format!(r#"Error cloning "{}" to "{}": {e}"#, from.display(), to.display()))
+			}
+			Op::Hardlink{from,to} => {
+				log::debug!(r#"Creating "{}" as a hardlink to "{}""#,to.display(),from.display());
+				self.touch(to)?;
+				atomic::hardlink(from, to)
+					.map_err(|e|format!(r#"Error linking "{}" to "{}": {e}"#, to.display(), from.display()))
+			}
+			Op::Write{to,content,attributes} => {
+				log::debug!(r#"Writing "{}""#,to.display());
+				self.touch(to)?;
+				atomic::write(to, content, attributes.as_ref())
+					.map_err(|e|format!(r#"Failed writing "{}": {e}"#, to.display()))
+			}
+			Op::Symlink{target,link} => {
+				log::debug!(r#"Linking "{}" to "{}""#,link.display(),target.display());
+				self.touch(link)?;
+				atomic::symlink(target, link)
+					.map_err(|e|format!(r#"Failed to link "{}" to "{}": {e}"#, link.display(), target.display()))
+			}
+			Op::Remove{path} => {
+				log::debug!(r#"Removing "{}""#,path.display());
+				self.touch(path)?;
+				std::fs::remove_file(path)
+					.and_then(|_|atomic::sync_parent(path))
+					.map_err(|e|format!(r#"Failed to remove file "{}": {e}"#, path.display()))
+			}
+			Op::CreateDir{path} => {
+				log::debug!(r#"Creating directory "{}""#,path.display());
+				std::fs::create_dir_all(path)
+					.and_then(|_|atomic::sync_parent(path))
+					.map_err(|e|format!(r#"Failed to create directory "{}": {e}"#, path.display()))
+			}
+			Op::RemoveDir{path} => {
+				log::debug!(r#"Removing directory "{}""#,path.display());
+				let entry = self.journal.files.iter().find(|e|e.path == *path && e.dir)
+					.ok_or(format!(r#"Directory "{}" doesn't exist"#, path.display()))?;
+				match &entry.backup {
+					// it might still contain backups, so it's moved out of the way until the transaction is committed
+					Some(backup) => std::fs::rename(path, backup),
+					// created by an earlier step, so everything that was in it was created and removed again as well
+					None => std::fs::remove_dir(path),
+				}.and_then(|_|atomic::sync_parent(path))
+					.map_err(|e|format!(r#"Failed to remove directory "{}": {e}"#, path.display()))
+			}
+		}
+	}
+
+	/// Apply all staged changes.
+	///
+	/// If any of them fails, all changes done so far are rolled back.
+	/// The backups are kept until [Transaction::commit] or [Transaction::rollback] is called.
+	pub fn apply(&mut self) -> Result<(),String> {
+		let ops = std::mem::take(&mut self.ops);
+		for op in &ops {
+			let path = op.target();
+			let dir = op.is_dir();
+			// a path can be both, e.g. when a directory is replaced by a symlink
+			if self.journal.files.iter().any(|e|e.path == path && e.dir == dir) {
+				continue;
+			}
+			let backup = path.symlink_metadata().is_ok_and(|m|m.is_dir() == dir).then(||backup_name(path));
+			self.journal.files.push(Entry{path:path.to_owned(), backup, dir});
+		}
+		self.journal.pid = std::process::id();
+		self.journal.save(&self.journal_path)?;
+		for op in &ops {
+			if let Err(e) = self.apply_op(op) {
+				let rolled_back = self.rollback();
+				return Err(format!("{e}\n{rolled_back}"));
+			}
+		}
+		Ok(())
+	}
+
+	/// Restore all files touched so far, and return a report of what was done.
+	pub fn rollback(&mut self) -> String {
+		self.ops.clear();
+		self.touched.clear();
+		let mut report = std::mem::take(&mut self.journal).roll_back();
+		if self.journal_path.exists() && let Err(e) = Journal::remove(&self.journal_path) {
+			report += &format!("\n{e}");
+		}
+		report
+	}
+
+	/// Drop all backups, making the applied changes permanent.
+	pub fn commit(mut self) {
+		if self.journal.files.is_empty() {
+			return;
+		}
+		self.journal.committed = true;
+		if let Err(e) = self.journal.save(&self.journal_path) {
+			log::warn!("{e}");
+		}
+		self.journal.remove_backups();
+		if let Err(e) = Journal::remove(&self.journal_path) {
+			log::warn!("{e}");
+		}
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	/// A fresh, empty directory for a test.
+	pub(crate) fn temp_dir(name:&str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("profile-rs-test.{}.{name}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	fn read(path:&Path) -> String {
+		std::fs::read_to_string(path).unwrap()
+	}
+
+	#[test]
+	fn failing_op_rolls_back() {
+		let dir = temp_dir("rollback");
+		let (changed,removed,created) = (dir.join("changed"), dir.join("removed"), dir.join("created"));
+		std::fs::write(&changed, "old").unwrap();
+		std::fs::write(&removed, "kept").unwrap();
+		let mut tx = Transaction::new(dir.join("journal"));
+		tx.write(&changed, b"new".to_vec());
+		tx.remove(&removed);
+		tx.write(&created, b"created".to_vec());
+		tx.copy(&dir.join("doesn't exist"), &dir.join("copy"));
+		let error = tx.apply().unwrap_err();
+		assert!(error.contains("Rolled back"), "{error}");
+		assert_eq!(read(&changed), "old");
+		assert_eq!(read(&removed), "kept");
+		assert!(!created.exists());
+		assert!(!dir.join("journal").exists());
+		// no backups are left behind
+		assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn commit_keeps_changes() {
+		let dir = temp_dir("commit");
+		let file = dir.join("file");
+		std::fs::write(&file, "old").unwrap();
+		let mut tx = Transaction::new(dir.join("journal"));
+		tx.write(&file, b"new".to_vec());
+		tx.create_dir(&dir.join("sub"));
+		tx.copy(&file, &dir.join("sub/copy"));
+		tx.apply().unwrap();
+		tx.commit();
+		assert_eq!(read(&file), "new");
+		assert_eq!(read(&dir.join("sub/copy")), "new");
+		assert!(!dir.join("journal").exists());
+		assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn removed_directory_is_restored() {
+		let dir = temp_dir("remove-dir");
+		let sub = dir.join("sub");
+		std::fs::create_dir(&sub).unwrap();
+		std::fs::write(sub.join("file"), "content").unwrap();
+		let mut tx = Transaction::new(dir.join("journal"));
+		tx.remove(&sub.join("file"));
+		tx.remove_dir(&sub);
+		tx.remove(&dir.join("doesn't exist"));
+		assert!(tx.apply().is_err());
+		assert_eq!(read(&sub.join("file")), "content");
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
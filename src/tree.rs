@@ -0,0 +1,116 @@
+//! Managed directories.
+//!
+//! A directory is managed as a whole: its variants are snapshots of the complete tree, and putting
+//! one in place adds, updates and removes entries until the directory has exactly the content of the
+//! snapshot. What a tree contains is described by a [Tree], its hash is the hash of that description.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::store::{hash_bytes, hash_file};
+use crate::transaction::Transaction;
+
+#[derive(Deserialize,Serialize,Debug,Clone,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Node {
+	Dir,
+	/// a regular file with the given content hash
+	File(String),
+	/// a symlink to the given target
+	Link(PathBuf),
+}
+
+/// All entries below a directory by their path relative to it
+pub type Tree = BTreeMap<PathBuf,Node>;
+
+/// Describe the current content of `dir`.
+pub fn read(dir:&Path) -> Result<Tree,String> {
+	let mut tree = Tree::new();
+	read_into(dir, Path::new(""), &mut tree)?;
+	Ok(tree)
+}
+
+fn read_into(root:&Path, relative:&Path, tree:&mut Tree) -> Result<(),String> {
+	let dir = root.join(relative);
+	let error = |e:std::io::Error|format!(r#"Failed reading directory "{}": {e}"#,dir.display());
+	for entry in std::fs::read_dir(&dir).map_err(error)? {
+		let entry = entry.map_err(error)?;
+		let relative = relative.join(entry.file_name());
+		let kind = entry.file_type().map_err(error)?;
+		if kind.is_dir() {
+			tree.insert(relative.clone(), Node::Dir);
+			read_into(root, &relative, tree)?;
+		} else if kind.is_symlink() {
+			let target = std::fs::read_link(entry.path()).map_err(error)?;
+			tree.insert(relative, Node::Link(target));
+		} else if kind.is_file() {
+			tree.insert(relative, Node::File(hash_file(&entry.path())?));
+		} else {
+			log::warn!(r#"Ignoring "{}", it's neither a file, a directory nor a symlink"#,entry.path().display());
+		}
+	}
+	Ok(())
+}
+
+pub fn serialize(tree:&Tree) -> Result<String,String> {
+	toml::to_string(tree).map_err(|e|format!("Failed to serialize directory tree: {e}"))
+}
+
+pub fn parse(text:&str, source:&Path) -> Result<Tree,String> {
+	toml::from_str(text).map_err(|e|format!(r#"Failed parse directory tree "{}":{e}"#,source.display()))
+}
+
+pub fn hash(tree:&Tree) -> Result<String,String> {
+	serialize(tree).map(|s|hash_bytes(s.as_bytes()))
+}
+
+/// The hash of the content of a file, or of a whole directory.
+pub fn hash_path(path:&Path) -> Result<String,String> {
+	if path.is_dir() {hash(&read(path)?)} else {hash_file(path)}
+}
+
+/// Stage removing `dir`, which has the content `tree`, with everything in it.
+pub fn remove(dir:&Path, tree:&Tree, tx:&mut Transaction) {
+	// deepest entries first
+	for (relative,node) in tree.iter().rev() {
+		match node {
+			Node::Dir => tx.remove_dir(&dir.join(relative)),
+			_ => tx.remove(&dir.join(relative)),
+		}
+	}
+	tx.remove_dir(dir);
+}
+
+/// Stage changing the directory `dir` from `current` (None if it doesn't exist) to `target`.
+///
+/// Files that differ are put in place by `put_file`, with their relative path, hash and the full path to create.
+pub fn sync<F>(dir:&Path, current:Option<&Tree>, target:&Tree, tx:&mut Transaction, mut put_file:F)
+where F:FnMut(&Path, &str, &Path, &mut Transaction)
+{
+	let empty = Tree::new();
+	let current = match current {
+		Some(current) => current,
+		None => {tx.create_dir(dir); &empty}
+	};
+	// removals first (deepest entries first), including entries that turn from directory to file or back
+	for (relative,node) in current.iter().rev() {
+		let path = dir.join(relative);
+		match (node, target.get(relative)) {
+			(Node::Dir, Some(Node::Dir)) => {}
+			(Node::Dir, _) => tx.remove_dir(&path),
+			(_, None | Some(Node::Dir)) => tx.remove(&path),
+			_ => {}
+		}
+	}
+	for (relative,node) in target {
+		let path = dir.join(relative);
+		let existing = current.get(relative);
+		if existing == Some(node) {
+			continue;
+		}
+		match node {
+			Node::Dir => tx.create_dir(&path),
+			Node::File(hash) => put_file(relative, hash, &path, tx),
+			Node::Link(target) => tx.symlink(target, &path),
+		}
+	}
+}
@@ -0,0 +1,134 @@
+//! Noticing changes of the managed files and the profiles file, for `watch`.
+//!
+//! The parent directories of the watched paths are watched rather than the paths themselves, as files
+//! are replaced by renaming new ones over them (by this tool as well as by most editors).
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+/// How long to wait for more changes after one, editors write in several steps.
+const SETTLE_MS:i32 = 200;
+
+pub struct Watcher {
+	inotify:Inotify,
+	/// the directory of each watch
+	dirs:HashMap<WatchDescriptor,PathBuf>,
+	/// watched directories, whose new subdirectories are watched as well
+	trees:Vec<PathBuf>,
+	/// readable once SIGTERM or SIGINT arrived
+	signals:UnixStream,
+	buffer:Vec<u8>,
+}
+
+fn mask() -> WatchMask {
+	WatchMask::CLOSE_WRITE | WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO
+}
+
+impl Watcher {
+	pub fn new() -> Result<Watcher,String> {
+		let inotify = Inotify::init().map_err(|e|format!("Failed to initialize inotify: {e}"))?;
+		let (signals,sender) = UnixStream::pair().map_err(|e|format!("Failed to create a socket for signals: {e}"))?;
+		for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+			let sender = sender.try_clone().map_err(|e|format!("Failed to create a socket for signals: {e}"))?;
+			signal_hook::low_level::pipe::register(signal, sender).map_err(|e|format!("Failed to register signal handler: {e}"))?;
+		}
+		Ok(Watcher{inotify, dirs:HashMap::new(), trees:vec![], signals, buffer:vec![0;4096]})
+	}
+
+	/// Watch `paths` (and the content of the ones that are directories) instead of what was watched so far.
+	pub fn watch<'a>(&mut self, paths:impl Iterator<Item=&'a PathBuf>) -> Result<(),String> {
+		// dropping the old instance removes all its watches
+		self.inotify = Inotify::init().map_err(|e|format!("Failed to initialize inotify: {e}"))?;
+		self.dirs.clear();
+		self.trees.clear();
+		for path in paths {
+			if let Some(parent) = path.parent() {
+				self.add(parent)?;
+			}
+			if path.is_dir() && !path.is_symlink() {
+				self.trees.push(path.clone());
+				self.add_tree(path)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn add(&mut self, dir:&Path) -> Result<(),String> {
+		match self.inotify.watches().add(dir, mask()) {
+			Ok(wd) => {self.dirs.insert(wd, dir.to_owned());}
+			Err(e) if e.kind() == ErrorKind::NotFound => log::warn!(r#"Can't watch "{}", it doesn't exist"#, dir.display()),
+			Err(e) => return Err(format!(r#"Failed to watch "{}": {e}"#, dir.display())),
+		}
+		Ok(())
+	}
+
+	fn add_tree(&mut self, dir:&Path) -> Result<(),String> {
+		self.add(dir)?;
+		let entries = std::fs::read_dir(dir).map_err(|e|format!(r#"Failed to read directory "{}": {e}"#, dir.display()))?;
+		for entry in entries.flatten() {
+			if entry.file_type().is_ok_and(|t|t.is_dir()) {
+				self.add_tree(&entry.path())?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Wait for changes and return the paths that changed, or None once SIGTERM or SIGINT arrived.
+	pub fn wait(&mut self) -> Result<Option<Vec<PathBuf>>,String> {
+		let mut changed = vec![];
+		let mut timeout = -1;
+		loop {
+			let mut fds = [
+				libc::pollfd{fd:self.inotify.as_raw_fd(), events:libc::POLLIN, revents:0},
+				libc::pollfd{fd:self.signals.as_raw_fd(), events:libc::POLLIN, revents:0},
+			];
+			// SAFETY: fds is a valid array of the given length
+			let ready = unsafe {libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout)};
+			if ready < 0 {
+				let e = std::io::Error::last_os_error();
+				if e.kind() == ErrorKind::Interrupted {
+					continue;
+				}
+				return Err(format!("Failed to wait for changes: {e}"));
+			}
+			if fds[1].revents != 0 {
+				return Ok(None);
+			}
+			if ready == 0 {
+				return Ok(Some(changed));
+			}
+			self.read(&mut changed)?;
+			timeout = SETTLE_MS;
+		}
+	}
+
+	fn read(&mut self, changed:&mut Vec<PathBuf>) -> Result<(),String> {
+		let events = match self.inotify.read_events(&mut self.buffer) {
+			Ok(events) => events,
+			Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+			Err(e) => return Err(format!("Failed to read changes: {e}")),
+		};
+		let mut new_dirs = vec![];
+		for event in events {
+			if event.mask.contains(EventMask::Q_OVERFLOW) {
+				log::warn!("Too many changes at once, some of them were missed");
+			}
+			let (Some(dir),Some(name)) = (self.dirs.get(&event.wd), event.name) else {continue};
+			let path = dir.join(name);
+			if event.mask.contains(EventMask::ISDIR) && event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO)
+				&& self.trees.iter().any(|t|path.starts_with(t)) {
+				new_dirs.push(path.clone());
+			}
+			if !changed.contains(&path) {
+				changed.push(path);
+			}
+		}
+		for dir in new_dirs {
+			self.add_tree(&dir)?;
+		}
+		Ok(())
+	}
+}